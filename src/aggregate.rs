@@ -0,0 +1,103 @@
+use serde_json::Value;
+use std::collections::HashMap;
+
+use crate::options::sort_cmp;
+
+/// Per-group statistics computed by `RedDb::group_by`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Stats {
+    pub count: usize,
+    pub sum: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Stats {
+    pub(crate) fn new(value: f64) -> Self {
+        Stats {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    pub(crate) fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+
+    pub fn avg(&self) -> f64 {
+        self.sum / self.count as f64
+    }
+}
+
+/// Counts equal values, most frequent first. Arrays are counted element by
+/// element.
+pub(crate) fn tally<I>(values: I) -> Vec<(Value, usize)>
+where
+    I: IntoIterator<Item = Value>,
+{
+    tally_counts(values.into_iter().map(|value| (value, 1)))
+}
+
+/// Same as `tally` for values that are already counted, like index keys.
+pub(crate) fn tally_counts<I>(values: I) -> Vec<(Value, usize)>
+where
+    I: IntoIterator<Item = (Value, usize)>,
+{
+    let mut counts: HashMap<String, (Value, usize)> = HashMap::new();
+    let mut add = |value: Value, count: usize| {
+        counts.entry(value.to_string()).or_insert((value, 0)).1 += count;
+    };
+    for (value, count) in values {
+        match value {
+            Value::Array(items) => items.into_iter().for_each(|item| add(item, count)),
+            value => add(value, count),
+        }
+    }
+    let mut counts: Vec<(Value, usize)> = counts.into_values().collect();
+    counts.sort_by(|(a, a_count), (b, b_count)| b_count.cmp(a_count).then_with(|| sort_cmp(a, b)));
+    counts
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn stats() {
+        let mut stats = Stats::new(2.0);
+        stats.add(4.0);
+        stats.add(9.0);
+        assert_eq!(stats.count, 3);
+        assert_eq!(stats.sum, 15.0);
+        assert_eq!(stats.min, 2.0);
+        assert_eq!(stats.max, 9.0);
+        assert_eq!(stats.avg(), 5.0);
+    }
+
+    #[test]
+    fn tally_values() {
+        let counts = tally(vec![
+            json!("a"),
+            json!(["b", "a"]),
+            json!(1),
+            json!("b"),
+            json!("a"),
+        ]);
+        assert_eq!(
+            counts,
+            vec![(json!("a"), 3), (json!("b"), 2), (json!(1), 1)]
+        );
+    }
+
+    #[test]
+    fn tally_counted_values() {
+        let counts = tally_counts(vec![(json!(["b", "a"]), 2), (json!("a"), 1)]);
+        assert_eq!(counts, vec![(json!("a"), 3), (json!("b"), 2)]);
+    }
+}
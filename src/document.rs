@@ -1,7 +1,10 @@
 use serde::{Deserialize, Serialize};
 use std::fmt::Debug;
 
+use crate::expiry::NEVER;
+use crate::schema::FIRST_VERSION;
 use crate::status::Status;
+use crate::transaction::Frame;
 use uuid::Uuid;
 
 #[derive(Debug, Serialize, Deserialize, Clone, PartialEq)]
@@ -9,6 +12,28 @@ pub struct Document<T> {
     pub _id: Uuid,
     pub data: T,
     pub _st: Status,
+    #[serde(default)]
+    pub _rev: u64,
+    /// When the document was inserted, in milliseconds since the Unix epoch.
+    #[serde(default)]
+    pub _created: u64,
+    /// When the document was last inserted or updated, in milliseconds since
+    /// the Unix epoch.
+    #[serde(default)]
+    pub _updated: u64,
+    /// When the document expires, in milliseconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub _exp: Option<u64>,
+    /// When a trashed document is purged, in milliseconds since the Unix
+    /// epoch. Trashed documents without it stay until the trash is emptied.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub _purge: Option<u64>,
+    #[serde(default = "first_version", skip_serializing_if = "is_first_version")]
+    pub(crate) _v: u32,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub(crate) _col: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub(crate) _tx: Option<Frame>,
 }
 
 impl<'a, T> Document<T>
@@ -20,6 +45,86 @@ where
             _id: id,
             data,
             _st: st,
+            _rev: 0,
+            _created: 0,
+            _updated: 0,
+            _exp: None,
+            _purge: None,
+            _v: FIRST_VERSION,
+            _col: String::new(),
+            _tx: None,
+        }
+    }
+}
+
+pub(crate) fn first_version() -> u32 {
+    FIRST_VERSION
+}
+
+fn is_first_version(version: &u32) -> bool {
+    *version == FIRST_VERSION
+}
+
+/// A document as kept in memory: its serialized value and metadata.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Record {
+    pub value: Vec<u8>,
+    pub rev: u64,
+    pub created: u64,
+    pub updated: u64,
+    pub expires: Option<u64>,
+    /// When the record is purged from the trash, `NEVER` until it is emptied.
+    /// `None` for records that aren't trashed.
+    pub trashed: Option<u64>,
+}
+
+impl Record {
+    /// A record inserted at `now`.
+    pub fn new(value: Vec<u8>, now: u64) -> Self {
+        Self {
+            value,
+            rev: 1,
+            created: now,
+            updated: now,
+            expires: None,
+            trashed: None,
+        }
+    }
+
+    /// Next revision of this record, holding `value` since `now`. It keeps
+    /// the creation time and the expiry.
+    pub fn update(&self, value: Vec<u8>, now: u64) -> Self {
+        Self {
+            value,
+            rev: self.rev + 1,
+            created: self.created,
+            updated: now,
+            expires: self.expires,
+            trashed: None,
+        }
+    }
+
+    /// Next revision of this record, moved to the trash at `now` until
+    /// `purge`.
+    pub fn trash(&self, now: u64, purge: u64) -> Self {
+        Self {
+            trashed: Some(purge),
+            ..self.update(self.value.clone(), now)
+        }
+    }
+
+    /// When the record is purged from the trash, if ever.
+    pub fn purge(&self) -> Option<u64> {
+        self.trashed.filter(|at| *at != NEVER)
+    }
+
+    /// `Tr` in the trash, otherwise `Up` once the record has been updated
+    /// since it was inserted.
+    pub fn status(&self) -> Status {
+        match (self.trashed, self.rev) {
+            (Some(_), _) => Status::Tr,
+            (None, 0) | (None, 1) => Status::In,
+            _ => Status::Up,
         }
     }
 }
@@ -1,3 +1,4 @@
+use std::error::Error;
 use std::fmt::{self, Display};
 use thiserror::Error;
 use uuid::Uuid;
@@ -30,6 +31,14 @@ pub enum RedDbErrorKind {
     // uuids
     #[error("Could not find _id {_id}")]
     NotFound { _id: Uuid },
+    #[error("_id {_id} already exists")]
+    DuplicateId { _id: Uuid },
+    #[error("Expected revision {expected} of _id {_id}, found {found}")]
+    Conflict {
+        _id: Uuid,
+        expected: u64,
+        found: u64,
+    },
     #[error("Could not delete _id")]
     Deletekey,
     #[error("Could not unlock mutex")]
@@ -43,22 +52,73 @@ pub enum RedDbErrorKind {
     Deserialization,
     #[error("Could not serialize data")]
     Serialization,
+    // QUERY
+    #[error("Invalid query")]
+    InvalidQuery,
+    #[error("Invalid cursor")]
+    InvalidCursor,
+    #[error("Invalid update")]
+    InvalidUpdate,
+    // INDEXES
+    #[error("Could not find index")]
+    IndexNotFound,
+    #[error("Index key or document type mismatch")]
+    IndexType,
+    #[error("Unique index {index} already contains _id {_id}")]
+    UniqueViolation { index: &'static str, _id: Uuid },
+    // COLLECTIONS
+    #[error("Invalid collection name")]
+    InvalidCollection,
+    #[error("Collection already open with another document type")]
+    CollectionType,
+    // MIGRATIONS
+    #[error("Could not migrate _id {_id} from version {version}")]
+    Migration { _id: Uuid, version: u32 },
+    // WATCH
+    #[error("Watcher missed {missed} changes")]
+    Lagged { missed: u64 },
+    // HOOKS
+    #[error("Write rejected")]
+    Rejected,
 }
 
 #[derive(Debug, Error)]
 pub struct RedDbError {
     err: RedDbErrorKind,
+    #[source]
+    reason: Option<Box<dyn Error + Send + Sync>>,
 }
 
 impl RedDbError {
+    pub(crate) fn rejected<E>(reason: E) -> RedDbError
+    where
+        E: Error + Send + Sync + 'static,
+    {
+        RedDbError {
+            err: RedDbErrorKind::Rejected,
+            reason: Some(Box::new(reason)),
+        }
+    }
+
     pub fn kind(&self) -> RedDbErrorKind {
         self.err
     }
+
+    /// The error a hook or `Validate` rejected the write with, if it is an `E`.
+    pub fn downcast_ref<E>(&self) -> Option<&E>
+    where
+        E: Error + 'static,
+    {
+        self.reason.as_ref()?.downcast_ref::<E>()
+    }
 }
 
 impl From<RedDbErrorKind> for RedDbError {
     fn from(kind: RedDbErrorKind) -> RedDbError {
-        RedDbError { err: kind }
+        RedDbError {
+            err: kind,
+            reason: None,
+        }
     }
 }
 
@@ -0,0 +1,62 @@
+use std::time::{Duration, SystemTime};
+
+use crate::document::Record;
+
+/// Versions of each document kept by compaction, set with
+/// `Schema::with_history`. The current version is always kept.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum History {
+    /// The last `n` versions, the current one included.
+    Versions(usize),
+    /// The versions that were current at some point of the last `Duration`.
+    Since(Duration),
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History::Versions(1)
+    }
+}
+
+/// A version of a document: the one with a revision or the one current at
+/// a time.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum At {
+    Rev(u64),
+    Time(SystemTime),
+}
+
+impl History {
+    /// Whether versions older than the current one are ever kept.
+    pub(crate) fn keeps_past(&self) -> bool {
+        !matches!(self, History::Versions(0) | History::Versions(1))
+    }
+
+    /// Drops the `versions` of a document, oldest first, that are no longer
+    /// kept at `now`. `updated` tells when a version was written.
+    pub(crate) fn retain<V, F>(&self, versions: &mut Vec<V>, updated: F, now: u64)
+    where
+        F: Fn(&V) -> u64,
+    {
+        let first = match *self {
+            History::Versions(count) => versions.len().saturating_sub(count.max(1)),
+            History::Since(period) => {
+                let since = now.saturating_sub(period.as_millis() as u64);
+                // a version is current until the next one is written
+                (1..versions.len())
+                    .find(|next| updated(&versions[*next]) >= since)
+                    .map_or(versions.len().saturating_sub(1), |next| next - 1)
+            }
+        };
+        versions.drain(..first);
+    }
+}
+
+/// Adds `record` as the current version of a document. A record rewriting
+/// the current revision, like an expiry change, replaces it.
+pub(crate) fn push(versions: &mut Vec<Record>, record: Record) {
+    if versions.last().is_some_and(|last| last.rev == record.rev) {
+        versions.pop();
+    }
+    versions.push(record);
+}
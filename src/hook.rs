@@ -0,0 +1,109 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt::{self, Debug};
+use uuid::Uuid;
+
+use crate::document::Document;
+use crate::error::{RedDbError, RedDbErrorKind, Result};
+use crate::serializer::Serializer;
+use crate::status::Status;
+
+/// Invariants of a document type, checked before every insert and update
+/// once registered with `RedDb::validate`.
+pub trait Validate {
+    type Error: Error + Send + Sync + 'static;
+
+    fn validate(&self) -> std::result::Result<(), Self::Error>;
+}
+
+type Before<SE> = Box<dyn Fn(&SE, &Uuid, &[u8]) -> Result<()> + Send + Sync>;
+type After<SE> = Box<dyn Fn(&SE, &Uuid, u64, &[u8]) + Send + Sync>;
+
+/// Hooks run around writes.
+///
+/// Every hook is registered for a `Status`: `In` for inserts, `Up` for
+/// updates and `De` for deletes. Before hooks run under the write lock and
+/// can reject the write; after hooks only observe it once it is persisted.
+pub(crate) struct Hooks<SE> {
+    before: Vec<(Status, Before<SE>)>,
+    after: Vec<(Status, After<SE>)>,
+}
+
+impl<SE> Default for Hooks<SE> {
+    fn default() -> Self {
+        Self {
+            before: Vec::new(),
+            after: Vec::new(),
+        }
+    }
+}
+
+impl<SE> Debug for Hooks<SE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Hooks")
+            .field("before", &self.before.len())
+            .field("after", &self.after.len())
+            .finish()
+    }
+}
+
+impl<SE> Hooks<SE>
+where
+    for<'de> SE: Serializer<'de>,
+{
+    /// Adds a hook that can reject a write. A value that isn't a `T` is
+    /// rejected too.
+    pub fn add_before<T, E, F>(&mut self, status: Status, hook: F)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + 'static,
+        E: Error + Send + Sync + 'static,
+        F: Fn(&Uuid, &T) -> std::result::Result<(), E> + Send + Sync + 'static,
+    {
+        let hook = move |serializer: &SE, id: &Uuid, value: &[u8]| -> Result<()> {
+            let value: T = serializer
+                .deserialize(value)
+                .map_err(|_| RedDbErrorKind::Deserialization)?;
+            hook(id, &value).map_err(RedDbError::rejected)
+        };
+        self.before.push((status, Box::new(hook)));
+    }
+
+    /// Adds a hook run once a write is persisted. It is skipped for values
+    /// that aren't a `T`.
+    pub fn add_after<T, F>(&mut self, status: Status, hook: F)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + 'static,
+        F: Fn(&Document<T>) + Send + Sync + 'static,
+    {
+        let st = status.clone();
+        let hook = move |serializer: &SE, id: &Uuid, rev: u64, value: &[u8]| {
+            if let Ok(value) = serializer.deserialize(value) {
+                let mut doc = Document::new(*id, value, st.clone());
+                doc._rev = rev;
+                hook(&doc);
+            }
+        };
+        self.after.push((status, Box::new(hook)));
+    }
+
+    pub fn has_after(&self) -> bool {
+        !self.after.is_empty()
+    }
+
+    pub fn before(&self, serializer: &SE, status: &Status, id: &Uuid, value: &[u8]) -> Result<()> {
+        for (st, hook) in self.before.iter() {
+            if st == status {
+                hook(serializer, id, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn after(&self, serializer: &SE, status: &Status, id: &Uuid, rev: u64, value: &[u8]) {
+        for (st, hook) in self.after.iter() {
+            if st == status {
+                hook(serializer, id, rev, value);
+            }
+        }
+    }
+}
@@ -0,0 +1,428 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
+use std::fmt::{self, Debug};
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::sync::{Mutex, OnceLock};
+use uuid::Uuid;
+
+use crate::error::{RedDbErrorKind, Result};
+use crate::query;
+use crate::serializer::Serializer;
+use crate::RedDbHM;
+
+/// Definition of an index built from field paths. Unlike indexes keyed by a
+/// closure it can be saved with the storage and rebuilt when the documents
+/// are loaded.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct IndexDef {
+    pub collection: String,
+    pub name: String,
+    pub kind: IndexKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum IndexKind {
+    /// Hash index over the field named like the index.
+    Field { unique: bool },
+    /// Full-text index over the string fields at `fields`.
+    Text { fields: Vec<String> },
+}
+
+/// Index names are `&'static str`, as given to `create_index`. Names read
+/// back from the storage are leaked once and shared by every later load.
+pub(crate) fn intern(name: &str) -> &'static str {
+    static NAMES: OnceLock<Mutex<HashSet<&'static str>>> = OnceLock::new();
+    let mut names = NAMES
+        .get_or_init(Default::default)
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    match names.get(name) {
+        Some(name) => name,
+        None => {
+            let name: &'static str = Box::leak(name.to_owned().into_boxed_str());
+            names.insert(name);
+            name
+        }
+    }
+}
+
+/// Secondary index kept in sync with the documents stored in `RedDbHM`.
+///
+/// Indexes receive the serialized document, so they can be stored without
+/// knowing the document type at the `RedDb` level.
+pub(crate) trait Indexer<SE>: Send + Sync {
+    fn insert(&mut self, serializer: &SE, id: &Uuid, value: &[u8]) -> Result<()>;
+    fn remove(&mut self, id: &Uuid);
+    fn clear(&mut self);
+    fn as_any(&self) -> &dyn Any;
+
+    /// Every indexed key with the number of documents holding it, for
+    /// indexes that can represent their keys as values.
+    fn counts(&self) -> Option<Vec<(Value, usize)>> {
+        None
+    }
+
+    /// Path of the document field the index is keyed on, for indexes built
+    /// from a field rather than an arbitrary extractor.
+    fn field(&self) -> Option<&str> {
+        None
+    }
+
+    fn rebuild(&mut self, serializer: &SE, data: &RedDbHM) -> Result<()> {
+        self.clear();
+        for (id, record) in data.iter() {
+            self.insert(serializer, id, &record.value)?;
+        }
+        Ok(())
+    }
+}
+
+pub(crate) struct Indexes<SE> {
+    indexes: HashMap<&'static str, Box<dyn Indexer<SE>>>,
+}
+
+impl<SE> Default for Indexes<SE> {
+    fn default() -> Self {
+        Self {
+            indexes: HashMap::new(),
+        }
+    }
+}
+
+impl<SE> Debug for Indexes<SE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_set().entries(self.indexes.keys()).finish()
+    }
+}
+
+impl<SE> Indexes<SE> {
+    pub fn add(&mut self, name: &'static str, index: Box<dyn Indexer<SE>>) {
+        self.indexes.insert(name, index);
+    }
+
+    /// Key counts of an index keyed on the field at `field`, if there is one.
+    pub fn field_counts(&self, field: &str) -> Option<Vec<(Value, usize)>> {
+        self.indexes
+            .values()
+            .find(|index| index.field() == Some(field))
+            .and_then(|index| index.counts())
+    }
+
+    pub fn get<I: 'static>(&self, name: &str) -> Result<&I> {
+        let index = self
+            .indexes
+            .get(name)
+            .ok_or(RedDbErrorKind::IndexNotFound)?;
+
+        Ok(index
+            .as_any()
+            .downcast_ref::<I>()
+            .ok_or(RedDbErrorKind::IndexType)?)
+    }
+
+    /// Indexes `value` as the new content of `id`. `data` still holds the
+    /// previous content, which is restored if any index rejects the write.
+    pub fn write(
+        &mut self,
+        serializer: &SE,
+        data: &RedDbHM,
+        id: &Uuid,
+        value: &[u8],
+    ) -> Result<()> {
+        self.write_all(serializer, data, &[(*id, value)])
+    }
+
+    /// Same as `write` for a batch: either every write is indexed or none.
+    pub fn write_all(
+        &mut self,
+        serializer: &SE,
+        data: &RedDbHM,
+        writes: &[(Uuid, &[u8])],
+    ) -> Result<()> {
+        let changes: Vec<(Uuid, Option<&[u8]>)> = writes
+            .iter()
+            .map(|(id, value)| (*id, Some(*value)))
+            .collect();
+        self.apply(serializer, data, &changes)
+    }
+
+    /// Applies a batch of writes (`Some`) and removals (`None`) atomically.
+    /// Removals go first, so a batch can hand a unique key over to another
+    /// document.
+    pub fn apply(
+        &mut self,
+        serializer: &SE,
+        data: &RedDbHM,
+        changes: &[(Uuid, Option<&[u8]>)],
+    ) -> Result<()> {
+        let (mut ordered, writes): (Vec<_>, Vec<_>) =
+            changes.iter().partition(|(_id, value)| value.is_none());
+        ordered.extend(writes);
+
+        for (pos, (id, value)) in ordered.iter().enumerate() {
+            if let Err(err) = self.replace(serializer, id, *value) {
+                let ids: Vec<Uuid> = ordered[..=pos].iter().map(|(id, _)| *id).collect();
+                self.restore(serializer, data, &ids);
+                return Err(err);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn remove(&mut self, id: &Uuid) {
+        for index in self.indexes.values_mut() {
+            index.remove(id);
+        }
+    }
+
+    fn replace(&mut self, serializer: &SE, id: &Uuid, value: Option<&[u8]>) -> Result<()> {
+        self.remove(id);
+        if let Some(value) = value {
+            for index in self.indexes.values_mut() {
+                index.insert(serializer, id, value)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Indexes `ids` with their content in `data` again, undoing changes
+    /// that were indexed but not stored.
+    pub fn restore(&mut self, serializer: &SE, data: &RedDbHM, ids: &[Uuid]) {
+        for id in ids {
+            self.remove(id);
+        }
+        for id in ids {
+            if let Some(record) = data.get(id) {
+                for index in self.indexes.values_mut() {
+                    // previous content was indexed before, so it can't be rejected now
+                    let _ = index.insert(serializer, id, &record.value);
+                }
+            }
+        }
+    }
+}
+
+pub(crate) type Extractor<T, K> = Box<dyn Fn(&T) -> Option<K> + Send + Sync>;
+pub(crate) type KeyValue<K> = fn(&K) -> Option<Value>;
+
+/// Hash index over the key returned by an extractor, giving O(1) lookups.
+/// Documents without a key (`None`) are left out of the index.
+pub(crate) struct HashIndex<T, K> {
+    name: &'static str,
+    unique: bool,
+    field: Option<&'static str>,
+    extractor: Extractor<T, K>,
+    key_value: KeyValue<K>,
+    entries: HashMap<K, HashSet<Uuid>>,
+    keys: HashMap<Uuid, K>,
+    _doc: PhantomData<fn(&T)>,
+}
+
+impl<T, K> HashIndex<T, K>
+where
+    K: Hash + Eq + Clone,
+{
+    pub fn new<F>(name: &'static str, unique: bool, extractor: F, key_value: KeyValue<K>) -> Self
+    where
+        F: Fn(&T) -> Option<K> + Send + Sync + 'static,
+    {
+        Self {
+            name,
+            unique,
+            field: None,
+            extractor: Box::new(extractor),
+            key_value,
+            entries: HashMap::new(),
+            keys: HashMap::new(),
+            _doc: PhantomData,
+        }
+    }
+
+    pub fn find(&self, key: &K) -> Vec<Uuid> {
+        self.entries
+            .get(key)
+            .map(|ids| ids.iter().copied().collect())
+            .unwrap_or_default()
+    }
+
+    /// Every key with the documents holding it.
+    pub fn groups(&self) -> impl Iterator<Item = (&K, &HashSet<Uuid>)> {
+        self.entries.iter()
+    }
+}
+
+impl<T: Serialize> HashIndex<T, String> {
+    /// Index over the field at `path`, named after it. Keys are the field
+    /// values encoded as JSON and documents without the field are left out.
+    pub fn for_field(path: &'static str, unique: bool) -> Self {
+        let mut index = Self::new(
+            path,
+            unique,
+            move |value: &T| {
+                let tree = serde_json::to_value(value).ok()?;
+                query::lookup(&tree, path).map(|field| field.to_string())
+            },
+            |key: &String| serde_json::from_str(key).ok(),
+        );
+        index.field = Some(path);
+        index
+    }
+}
+
+impl<SE, T, K> Indexer<SE> for HashIndex<T, K>
+where
+    for<'de> SE: Serializer<'de>,
+    for<'de> T: Serialize + Deserialize<'de> + 'static,
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+{
+    fn insert(&mut self, serializer: &SE, id: &Uuid, value: &[u8]) -> Result<()> {
+        let value: T = serializer
+            .deserialize(value)
+            .map_err(|_| RedDbErrorKind::Deserialization)?;
+        let key = match (self.extractor)(&value) {
+            Some(key) => key,
+            None => return Ok(()),
+        };
+
+        if self.unique {
+            let other = self
+                .entries
+                .get(&key)
+                .and_then(|ids| ids.iter().find(|other| *other != id));
+            if let Some(other) = other {
+                return Err(RedDbErrorKind::UniqueViolation {
+                    index: self.name,
+                    _id: *other,
+                }
+                .into());
+            }
+        }
+
+        self.entries.entry(key.clone()).or_default().insert(*id);
+        self.keys.insert(*id, key);
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &Uuid) {
+        if let Some(key) = self.keys.remove(id) {
+            if let Some(ids) = self.entries.get_mut(&key) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.entries.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.keys.clear();
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn counts(&self) -> Option<Vec<(Value, usize)>> {
+        self.entries
+            .iter()
+            .map(|(key, ids)| Some(((self.key_value)(key)?, ids.len())))
+            .collect()
+    }
+
+    fn field(&self) -> Option<&str> {
+        self.field
+    }
+}
+
+/// Ordered index backed by a `BTreeMap`, used for range queries. Documents
+/// sharing a key are ordered by `_id`.
+pub(crate) struct OrderedIndex<T, K> {
+    extractor: Extractor<T, K>,
+    key_value: KeyValue<K>,
+    tree: BTreeMap<K, BTreeSet<Uuid>>,
+    keys: HashMap<Uuid, K>,
+}
+
+impl<T, K> OrderedIndex<T, K>
+where
+    K: Ord + Clone,
+{
+    pub fn new<F>(extractor: F, key_value: KeyValue<K>) -> Self
+    where
+        F: Fn(&T) -> Option<K> + Send + Sync + 'static,
+    {
+        Self {
+            extractor: Box::new(extractor),
+            key_value,
+            tree: BTreeMap::new(),
+            keys: HashMap::new(),
+        }
+    }
+
+    pub fn range<R>(&self, range: R, reverse: bool) -> Vec<Uuid>
+    where
+        R: RangeBounds<K>,
+    {
+        let entries = self.tree.range(range);
+        if reverse {
+            entries
+                .rev()
+                .flat_map(|(_key, ids)| ids.iter().rev().copied())
+                .collect()
+        } else {
+            entries
+                .flat_map(|(_key, ids)| ids.iter().copied())
+                .collect()
+        }
+    }
+}
+
+impl<SE, T, K> Indexer<SE> for OrderedIndex<T, K>
+where
+    for<'de> SE: Serializer<'de>,
+    for<'de> T: Serialize + Deserialize<'de> + 'static,
+    K: Ord + Clone + Send + Sync + 'static,
+{
+    fn insert(&mut self, serializer: &SE, id: &Uuid, value: &[u8]) -> Result<()> {
+        let value: T = serializer
+            .deserialize(value)
+            .map_err(|_| RedDbErrorKind::Deserialization)?;
+        if let Some(key) = (self.extractor)(&value) {
+            self.tree.entry(key.clone()).or_default().insert(*id);
+            self.keys.insert(*id, key);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &Uuid) {
+        if let Some(key) = self.keys.remove(id) {
+            if let Some(ids) = self.tree.get_mut(&key) {
+                ids.remove(id);
+                if ids.is_empty() {
+                    self.tree.remove(&key);
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.tree.clear();
+        self.keys.clear();
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+
+    fn counts(&self) -> Option<Vec<(Value, usize)>> {
+        self.tree
+            .iter()
+            .map(|(key, ids)| Some(((self.key_value)(key)?, ids.len())))
+            .collect()
+    }
+}
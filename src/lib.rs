@@ -1,28 +1,117 @@
-use futures::stream::{self, StreamExt};
+use futures::future;
+use futures::stream::{self, Stream, StreamExt};
 use futures::TryStreamExt;
+pub use serde_json::Value;
+use std::any::TypeId;
 use std::collections::HashMap;
 use std::fmt::Debug;
-use std::sync::Arc;
+use std::future::Future;
+use std::hash::Hash;
+use std::iter;
+use std::ops::RangeBounds;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Weak};
 use std::thread;
+use std::time::{Duration, SystemTime};
 use tokio::runtime::Runtime;
-use tokio::sync::{RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::sync::broadcast::{self, RecvError};
+use tokio::sync::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+use tokio::task::JoinHandle;
+use tokio::time;
 pub use uuid::Uuid;
 
+mod aggregate;
 mod document;
 mod error;
+mod expiry;
+mod history;
+mod hook;
+mod index;
+mod options;
+mod query;
+mod schema;
 pub mod serializer;
 mod status;
 mod storage;
+mod text;
+mod transaction;
+mod typed;
+mod update;
+mod watch;
 
+pub use aggregate::Stats;
 pub use document::Document;
-use error::{RedDbErrorKind, Result};
+use document::Record;
+use error::Result;
+pub use error::{RedDbError, RedDbErrorKind};
+use expiry::{timestamp, NEVER};
+pub use expiry::{Clock, SystemClock};
+pub use history::{At, History};
+use hook::Hooks;
+pub use hook::Validate;
+use index::{HashIndex, IndexDef, IndexKind, Indexer, Indexes, OrderedIndex};
+pub use options::{FindOptions, Order, Page, Sort};
+pub use query::{Field, Pattern, Query};
+pub use schema::{MigrationReport, Schema};
 use serde::{Deserialize, Serialize};
 use serializer::Serializer;
-use status::Status;
+pub use status::Status;
 pub use storage::FileStorage;
 use storage::Storage;
+use text::TextIndex;
+pub use transaction::Transaction;
+use transaction::{Frame, Op};
+pub use typed::TypedDb;
+pub use update::Update;
+pub use watch::Change;
+use watch::{Event, WATCH_CAPACITY};
 
-type RedDbHM = HashMap<Uuid, Vec<u8>>;
+type RedDbHM = HashMap<Uuid, Record>;
+/// Kept versions of each document, oldest first and the current one last.
+type Versions = HashMap<Uuid, Vec<Record>>;
+/// Open collections with the document type they were loaded as.
+type Collections<SE, ST> = HashMap<String, (TypeId, Weak<RedDb<SE, ST>>)>;
+
+/// Current version of every loaded document, with all the loaded versions
+/// when `history` keeps older ones too.
+fn split_versions(versions: Versions, history: &History) -> (RedDbHM, Versions) {
+    let data = versions
+        .iter()
+        .filter_map(|(id, kept)| Some((*id, kept.last()?.clone())))
+        .collect();
+    if history.keeps_past() {
+        (data, versions)
+    } else {
+        (data, Versions::new())
+    }
+}
+
+/// The version of `versions` with a revision, or the one current at a time.
+fn version_at<T>(versions: Vec<Document<T>>, at: At) -> Option<Document<T>> {
+    match at {
+        At::Rev(rev) => versions.into_iter().find(|doc| doc._rev == rev),
+        At::Time(time) => versions
+            .into_iter()
+            .rev()
+            .find(|doc| doc._updated <= timestamp(time)),
+    }
+}
+
+/// Splits loaded records into the trashed ones and the others.
+fn split_trash(data: RedDbHM) -> (RedDbHM, RedDbHM) {
+    data.into_iter()
+        .partition(|(_id, record)| record.trashed.is_some())
+}
+
+/// When the first document of `data` expires.
+fn next_expiry(data: &RedDbHM) -> u64 {
+    data.values()
+        .filter_map(|record| record.expires)
+        .min()
+        .unwrap_or(NEVER)
+}
+
+const STREAM_CHUNK_SIZE: usize = 512;
 
 #[cfg(feature = "bin_ser")]
 pub type BinDb = RedDb<serializer::Bin, FileStorage<serializer::Bin>>;
@@ -33,203 +122,641 @@ pub type YamlDb = RedDb<serializer::Yaml, FileStorage<serializer::Yaml>>;
 #[cfg(feature = "ron_ser")]
 pub type RonDb = RedDb<serializer::Ron, FileStorage<serializer::Ron>>;
 
+#[cfg(feature = "bin_ser")]
+pub type TypedBinDb<T> = TypedDb<T, serializer::Bin, FileStorage<serializer::Bin>>;
+#[cfg(feature = "json_ser")]
+pub type TypedJsonDb<T> = TypedDb<T, serializer::Json, FileStorage<serializer::Json>>;
+#[cfg(feature = "yaml_ser")]
+pub type TypedYamlDb<T> = TypedDb<T, serializer::Yaml, FileStorage<serializer::Yaml>>;
+#[cfg(feature = "ron_ser")]
+pub type TypedRonDb<T> = TypedDb<T, serializer::Ron, FileStorage<serializer::Ron>>;
+
+/// A database, or one of its collections, stored with `ST` and serialized
+/// with `SE`.
+///
+/// Indexes over field paths (`create_field_index`, `create_unique_field`,
+/// `create_text_index`) are saved with the storage and rebuilt whenever the documents are loaded.
+/// Indexes keyed by a closure can't be saved: they live in memory only and
+/// must be created again after opening the database, or lookups fail with
+/// `IndexNotFound`.
 #[derive(Debug)]
 pub struct RedDb<SE, ST> {
-    storage: ST,
+    storage: Arc<ST>,
     serializer: SE,
+    version: u32,
     data: Arc<RwLock<RedDbHM>>,
+    indexes: RwLock<Indexes<SE>>,
+    collection: String,
+    collections: Arc<Mutex<Collections<SE, ST>>>,
+    events: broadcast::Sender<Arc<Event>>,
+    hooks: RwLock<Hooks<SE>>,
+    clock: Arc<dyn Clock>,
+    next_expiry: AtomicU64,
+    trash: RwLock<RedDbHM>,
+    soft_delete: bool,
+    retention: Option<Duration>,
+    history: History,
+    versions: RwLock<Versions>,
 }
 
 impl<'a, SE, ST: 'static> RedDb<SE, ST>
 where
-    for<'de> SE: Serializer<'de> + Debug,
+    for<'de> SE: Serializer<'de> + Debug + Send + Sync + 'static,
     for<'de> ST: Storage + Debug + Send + Sync,
 {
     pub fn new<T>(db_name: &'static str) -> Result<Self>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
+    {
+        Self::with_schema::<T>(db_name, Schema::default())
+    }
+
+    /// Opens the database migrating documents older than `schema` to its
+    /// version. Fails if any of them can't be migrated.
+    pub fn with_schema<T>(db_name: &'static str, schema: Schema<SE>) -> Result<Self>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
+    {
+        Self::with_schema_and_clock::<T, _>(db_name, schema, SystemClock)
+    }
+
+    /// Same as `with_schema`, using `clock` instead of the system clock from
+    /// the start, so loading the documents already sees its time.
+    pub fn with_schema_and_clock<T, C>(
+        db_name: &'static str,
+        schema: Schema<SE>,
+        clock: C,
+    ) -> Result<Self>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
+        C: Clock + 'static,
     {
+        let clock: Arc<dyn Clock> = Arc::new(clock);
+        let now = clock.now();
         let mut rt = Runtime::new().unwrap();
+        let version = schema.version();
+        let history = schema.history();
 
-        let (data, storage) = thread::spawn(move || {
-            let storage = rt.block_on(async { ST::new(db_name).await.unwrap() });
-            let data = rt.block_on(async { storage.load::<T>().await.unwrap() });
-            (data, storage)
+        let (versions, definitions, storage) = thread::spawn(move || {
+            rt.block_on(async {
+                let storage = ST::new(db_name).await?;
+                let versions = storage.load::<T, SE>("", &schema, now).await?;
+                let definitions = storage.indexes("").await?;
+                Ok::<_, RedDbError>((versions, definitions, storage))
+            })
         })
         .join()
-        .map_err(|_| RedDbErrorKind::Datapersist)?;
+        .map_err(|_| RedDbErrorKind::Datapersist)??;
+        let (data, versions) = split_versions(versions, &history);
+        let (trash, data) = split_trash(data);
+        let indexes = Self::rebuild_indexes::<T>(definitions, &data)?;
 
         Ok(Self {
-            storage,
+            storage: Arc::new(storage),
+            next_expiry: AtomicU64::new(next_expiry(&data)),
             data: Arc::new(RwLock::new(data)),
+            indexes: RwLock::new(indexes),
             serializer: SE::default(),
+            version,
+            collection: String::new(),
+            collections: Arc::new(Mutex::new(HashMap::new())),
+            events: broadcast::channel(WATCH_CAPACITY).0,
+            hooks: RwLock::new(Hooks::default()),
+            clock,
+            trash: RwLock::new(trash),
+            soft_delete: false,
+            retention: None,
+            history,
+            versions: RwLock::new(versions),
         })
     }
 
-    async fn read(&'a self) -> Result<RwLockReadGuard<'a, RedDbHM>> {
-        let lock = self.data.read().await;
-        Ok(lock)
+    /// Builds the indexes saved with the storage over the loaded `data`.
+    fn rebuild_indexes<T>(definitions: Vec<IndexDef>, data: &RedDbHM) -> Result<Indexes<SE>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+    {
+        let serializer = SE::default();
+        let mut indexes = Indexes::default();
+        for definition in definitions {
+            let name = index::intern(&definition.name);
+            let mut index: Box<dyn Indexer<SE>> = match definition.kind {
+                IndexKind::Field { unique } => {
+                    Box::new(HashIndex::<T, String>::for_field(name, unique))
+                }
+                IndexKind::Text { fields } => Box::new(TextIndex::<T>::for_fields(fields)),
+            };
+            index.rebuild(&serializer, data)?;
+            indexes.add(name, index);
+        }
+        Ok(indexes)
     }
 
-    async fn write(&'a self) -> Result<RwLockWriteGuard<'a, RedDbHM>> {
-        let lock = self.data.write().await;
-        Ok(lock)
+    /// Uses `clock` instead of the system clock to expire documents, here
+    /// and in the collections opened afterwards. The documents of the
+    /// database are already loaded by then; open it with
+    /// `with_schema_and_clock` to load them with `clock` too.
+    pub fn with_clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Arc::new(clock);
+        self
+    }
+
+    /// Moves deleted documents to the trash instead of deleting them, here
+    /// and in the collections opened afterwards. They stay there until
+    /// `empty_trash` or, with a `retention`, until the database is loaded
+    /// once it has elapsed.
+    pub fn with_soft_delete(mut self, retention: Option<Duration>) -> Self {
+        self.soft_delete = true;
+        self.retention = retention;
+        self
     }
 
-    fn create_doc<T>(&self, id: &Uuid, value: T, status: Status) -> Document<T>
+    /// Reports which documents of `collection` in `db_name` would be
+    /// migrated by `schema` and which would fail, without changing the file.
+    /// The documents of the database itself are in the collection `""`.
+    pub async fn check_schema<T>(
+        db_name: &str,
+        collection: &str,
+        schema: &Schema<SE>,
+    ) -> Result<MigrationReport>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
     {
-        Document::new(*id, value, status)
+        let storage = ST::new(db_name).await?;
+        storage
+            .check::<T, SE>(collection, schema, SystemTime::now())
+            .await
     }
 
-    async fn find_uuids<T>(&self, search: &T) -> Result<Vec<Uuid>>
+    /// Opens the collection `name` stored in the same file as this database.
+    /// Its documents are loaded as `T` the first time it is opened; later
+    /// calls return the same collection while it is still in use, and fail
+    /// with `CollectionType` if `T` is not the type it was opened with.
+    pub async fn collection<T>(&self, name: &str) -> Result<Arc<Self>>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
     {
-        let data = self
-            .read()
+        self.collection_with_schema::<T>(name, &Schema::default())
             .await
-            .map_err(|_| RedDbErrorKind::PoisonedValue)
-            .unwrap();
+    }
 
-        let serialized = self.serialize(search)?;
+    /// Same as `collection`, migrating its documents with `schema` when it
+    /// is loaded. The schema is ignored if the collection is already open.
+    pub async fn collection_with_schema<T>(
+        &self,
+        name: &str,
+        schema: &Schema<SE>,
+    ) -> Result<Arc<Self>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
+    {
+        // the unnamed collection holds the documents of the database itself
+        if name.is_empty() {
+            return Err(RedDbErrorKind::InvalidCollection.into());
+        }
 
-        let docs: Vec<Uuid> = data
-            .iter()
-            .filter(|(_id, value)| **value == serialized)
-            .map(|(_id, _value)| *_id)
-            .collect();
+        let mut collections = self.collections.lock().await;
+        if let Some((type_id, collection)) = collections.get(name) {
+            if let Some(collection) = collection.upgrade() {
+                if *type_id != TypeId::of::<T>() {
+                    return Err(RedDbErrorKind::CollectionType.into());
+                }
+                return Ok(collection);
+            }
+        }
 
-        Ok(docs)
+        let versions = self
+            .storage
+            .load::<T, SE>(name, schema, self.clock.now())
+            .await?;
+        let (data, versions) = split_versions(versions, &schema.history());
+        let (trash, data) = split_trash(data);
+        let definitions = self.storage.indexes(name).await?;
+        let indexes = Self::rebuild_indexes::<T>(definitions, &data)?;
+        let collection = Arc::new(Self {
+            storage: Arc::clone(&self.storage),
+            next_expiry: AtomicU64::new(next_expiry(&data)),
+            data: Arc::new(RwLock::new(data)),
+            indexes: RwLock::new(indexes),
+            serializer: SE::default(),
+            version: schema.version(),
+            collection: name.to_owned(),
+            collections: Arc::clone(&self.collections),
+            events: broadcast::channel(WATCH_CAPACITY).0,
+            hooks: RwLock::new(Hooks::default()),
+            clock: Arc::clone(&self.clock),
+            trash: RwLock::new(trash),
+            soft_delete: self.soft_delete,
+            retention: self.retention,
+            history: schema.history(),
+            versions: RwLock::new(versions),
+        });
+        collections.insert(
+            name.to_owned(),
+            (TypeId::of::<T>(), Arc::downgrade(&collection)),
+        );
+
+        Ok(collection)
     }
 
-    async fn insert_document<T>(&self, value: T) -> Result<Document<T>>
+    /// Binds this database to the document type `T`.
+    pub fn typed<T>(self) -> TypedDb<T, SE, ST>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
     {
-        let mut data = self.write().await?;
-        let id = Uuid::new_v4();
-        let serialized = self.serialize(&value)?;
-        data.insert(id, serialized);
-        let result = self.create_doc(&id, value, Status::default());
-
-        Ok(result)
+        TypedDb::from_db(Arc::new(self))
     }
 
-    pub async fn insert_one<T>(&self, value: T) -> Result<Document<T>>
+    /// Same as `collection`, returning a handle bound to `T`.
+    pub async fn typed_collection<T>(&self, name: &str) -> Result<TypedDb<T, SE, ST>>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + Clone + PartialEq + Send + Sync,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
     {
-        let doc = self.insert_document(value).await?;
-        self.storage
-            .persist(&[doc.to_owned()])
+        Ok(TypedDb::from_db(self.collection::<T>(name).await?))
+    }
+
+    async fn read(&'a self) -> Result<RwLockReadGuard<'a, RedDbHM>> {
+        loop {
+            let lock = self.data.read().await;
+            // expired documents are deleted before anyone can read them
+            if self.now() < self.next_expiry.load(Ordering::SeqCst) {
+                return Ok(lock);
+            }
+            drop(lock);
+            self.reap().await?;
+        }
+    }
+
+    async fn write(&'a self) -> Result<RwLockWriteGuard<'a, RedDbHM>> {
+        let mut lock = self.data.write().await;
+        self.expire(&mut lock).await?;
+        Ok(lock)
+    }
+
+    /// Persists a delete for each of `ids`, whose records are in `records`.
+    async fn append_deletes(&self, records: &RedDbHM, ids: &[Uuid]) -> Result<()> {
+        // deletes are replayed without their data
+        let docs: Vec<Document<()>> = ids
+            .iter()
+            .map(|id| self.create_doc(id, (), Status::De, &records[id]))
+            .collect();
+        if !docs.is_empty() {
+            self.append(&docs).await?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the expired documents of `data`, persisting a delete for each
+    /// of them. Before hooks don't run, as an expiry can't be rejected.
+    async fn expire(&self, data: &mut RedDbHM) -> Result<usize> {
+        let now = self.now();
+        if now < self.next_expiry.load(Ordering::SeqCst) {
+            return Ok(0);
+        }
+
+        let expired: Vec<Uuid> = data
+            .iter()
+            .filter(|(_id, record)| record.expires.is_some_and(|at| at <= now))
+            .map(|(id, _record)| *id)
+            .collect();
+        self.append_deletes(data, &expired).await?;
+
+        let mut indexes = self.indexes.write().await;
+        let mut events: Vec<Event> = Vec::new();
+        for id in expired {
+            if let Some(record) = data.remove(&id) {
+                indexes.remove(&id);
+                events.push(Event {
+                    id,
+                    status: Status::De,
+                    rev: record.rev,
+                    before: Some(record.value),
+                    after: None,
+                });
+            }
+        }
+        self.next_expiry.store(next_expiry(data), Ordering::SeqCst);
+
+        let count = events.len();
+        self.notify(&*self.hooks.read().await, events);
+        Ok(count)
+    }
+
+    /// Current time of the clock, as documents are stamped.
+    fn now(&self) -> u64 {
+        timestamp(self.clock.now())
+    }
+
+    /// Deletes the documents that have expired and returns how many.
+    /// Reads never return expired documents anyway, this only frees them.
+    pub async fn reap(&self) -> Result<usize> {
+        let mut data = self.data.write().await;
+        self.expire(&mut data).await
+    }
+
+    /// Spawns a task reaping expired documents every `interval`, which
+    /// stops once the database is dropped.
+    pub fn spawn_reaper(self: &Arc<Self>, interval: Duration) -> JoinHandle<()> {
+        let db = Arc::downgrade(self);
+        tokio::spawn(async move {
+            loop {
+                time::delay_for(interval).await;
+                let db = match db.upgrade() {
+                    Some(db) => db,
+                    None => break,
+                };
+                // a failed reap is retried on the next tick
+                let _ = db.reap().await;
+            }
+        })
+    }
+
+    /// Runs the before hooks registered for `status`, which can reject the
+    /// write of `value`.
+    async fn before_write(&self, status: &Status, id: &Uuid, value: &[u8]) -> Result<()> {
+        self.hooks
+            .read()
             .await
-            .map_err(|_| RedDbErrorKind::Datapersist)?;
-        Ok(doc)
+            .before(&self.serializer, status, id, value)
     }
 
-    pub async fn insert<T>(&self, values: Vec<T>) -> Result<Vec<Document<T>>>
+    /// Persists `docs`, then runs the after hooks and notifies watchers.
+    async fn persist_docs<T>(
+        &self,
+        docs: &[Document<T>],
+        previous: Vec<Option<Vec<u8>>>,
+    ) -> Result<()>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
     {
-        let docs: Vec<Document<T>> = stream::iter(values)
-            .then(|data| self.insert_document(data))
-            .try_collect()
-            .await?;
+        self.append(docs).await?;
+        self.publish(docs, previous).await;
+        Ok(())
+    }
 
+    /// Persists `docs` and keeps their versions for `history`.
+    async fn append<T>(&self, docs: &[Document<T>]) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
         self.storage
-            .persist(&docs)
+            .persist(docs)
             .await
             .map_err(|_| RedDbErrorKind::Datapersist)?;
+        self.keep_versions(docs).await
+    }
 
-        Ok(docs)
+    /// Adds the just persisted `docs` to the kept versions, as loading them
+    /// from the storage would.
+    async fn keep_versions<T>(&self, docs: &[Document<T>]) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        if !self.history.keeps_past() {
+            return Ok(());
+        }
+
+        let now = self.now();
+        let mut versions = self.versions.write().await;
+        for doc in docs {
+            if let Status::De = doc._st {
+                versions.remove(&doc._id);
+                continue;
+            }
+            let record = Record {
+                value: self.serialize(&doc.data)?,
+                rev: doc._rev,
+                created: doc._created,
+                updated: doc._updated,
+                expires: doc._exp,
+                trashed: match doc._st {
+                    Status::Tr => Some(doc._purge.unwrap_or(NEVER)),
+                    _ => None,
+                },
+            };
+            let kept = versions.entry(doc._id).or_default();
+            history::push(kept, record);
+            self.history.retain(kept, |record| record.updated, now);
+        }
+        Ok(())
     }
 
-    pub async fn find_one<T>(&self, id: &Uuid) -> Result<Document<T>>
+    /// Runs the after hooks and sends a change for every document. `previous` holds the value each
+    /// updated document had before, in the order of `docs`; missing entries
+    /// are `None`, and deleted documents carry their own value.
+    async fn publish<T>(&self, docs: &[Document<T>], previous: Vec<Option<Vec<u8>>>)
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
     {
-        let data = self
-            .read()
-            .await
-            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let hooks = self.hooks.read().await;
+        // nothing to serialize when nobody is watching
+        if self.events.receiver_count() == 0 && !hooks.has_after() {
+            return;
+        }
+
+        let previous = previous.into_iter().chain(iter::repeat(None));
+        let events = docs
+            .iter()
+            .zip(previous)
+            .map(|(doc, before)| {
+                let value = self.serialize(&doc.data).ok();
+                let (before, after) = match doc._st {
+                    Status::De | Status::Tr => (value, None),
+                    _ => (before, value),
+                };
+                Event {
+                    id: doc._id,
+                    status: doc._st.clone(),
+                    rev: doc._rev,
+                    before,
+                    after,
+                }
+            })
+            .collect();
+        self.notify(&hooks, events);
+    }
 
-        let data = data.get(&id).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+    /// Runs the after hooks for `events` and sends them to the watchers.
+    fn notify(&self, hooks: &Hooks<SE>, events: Vec<Event>) {
+        for event in events {
+            // hooks of deletes get the deleted value, trashing is a delete too
+            let (status, value) = match event.status {
+                Status::De | Status::Tr => (Status::De, &event.before),
+                _ => (event.status.clone(), &event.after),
+            };
+            if let Some(value) = value {
+                hooks.after(&self.serializer, &status, &event.id, event.rev, value);
+            }
+            // sending only fails when every watcher is gone
+            let _ = self.events.send(Arc::new(event));
+        }
+    }
 
-        let data = self.deserialize(&*data)?;
-        let doc = self.create_doc(id, data, Status::In);
-        Ok(doc)
+    /// Streams every change persisted from now on. A watcher that falls
+    /// more than `WATCH_CAPACITY` changes behind gets a `Lagged` error
+    /// and continues with the most recent ones.
+    pub fn watch<T>(&self) -> impl Stream<Item = Result<Change<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        stream::unfold(self.events.subscribe(), |mut events| async move {
+            let change = match events.recv().await {
+                Ok(event) => event.decode(&SE::default()),
+                Err(RecvError::Lagged(missed)) => Err(RedDbErrorKind::Lagged { missed }.into()),
+                // the database was dropped, no change will come anymore
+                Err(RecvError::Closed) => return None,
+            };
+            Some((change, events))
+        })
     }
 
-    pub async fn update_one<T>(&'a self, id: &Uuid, new_value: T) -> Result<bool>
+    /// Same as `watch` for the changes of a single document.
+    pub fn watch_id<T>(&self, id: &Uuid) -> impl Stream<Item = Result<Change<T>>>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
     {
-        let mut data = self
-            .write()
-            .await
-            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let id = *id;
+        self.watch().filter(move |change: &Result<Change<T>>| {
+            future::ready(change.as_ref().map_or(true, |change| change._id == id))
+        })
+    }
 
-        if data.contains_key(id) {
-            let data = data
-                .get_mut(&id)
-                .ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+    /// Same as `watch` for the changes of documents matching `predicate`
+    /// before or after the change, so a live query sees documents leave it.
+    pub fn watch_where<T, F>(&self, predicate: F) -> impl Stream<Item = Result<Change<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        F: Fn(&T) -> bool,
+    {
+        self.watch().filter(move |change: &Result<Change<T>>| {
+            let matches = match change {
+                Ok(change) => {
+                    change.before.as_ref().is_some_and(&predicate)
+                        || change.after.as_ref().is_some_and(&predicate)
+                }
+                Err(_) => true,
+            };
+            future::ready(matches)
+        })
+    }
 
-            *data = self.serialize(&new_value)?;
-            let doc = self.create_doc(id, new_value, Status::Up);
+    /// Runs `hook` before every insert of a `T`. An error rejects the write
+    /// with `Rejected` before anything is stored.
+    pub async fn before_insert<T, E, F>(&self, hook: F)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        F: Fn(&Uuid, &T) -> std::result::Result<(), E> + Send + Sync + 'static,
+    {
+        self.hooks.write().await.add_before(Status::In, hook);
+    }
 
-            self.storage
-                .persist(&[doc])
-                .await
-                .map_err(|_| RedDbErrorKind::Datapersist)?;
+    /// Same as `before_insert` for updates, with the new value.
+    pub async fn before_update<T, E, F>(&self, hook: F)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        F: Fn(&Uuid, &T) -> std::result::Result<(), E> + Send + Sync + 'static,
+    {
+        self.hooks.write().await.add_before(Status::Up, hook);
+    }
 
-            Ok(true)
-        } else {
-            Ok(false)
-        }
+    /// Same as `before_insert` for deletes, with the value being deleted.
+    pub async fn before_delete<T, E, F>(&self, hook: F)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + 'static,
+        E: std::error::Error + Send + Sync + 'static,
+        F: Fn(&Uuid, &T) -> std::result::Result<(), E> + Send + Sync + 'static,
+    {
+        self.hooks.write().await.add_before(Status::De, hook);
     }
 
-    pub async fn remove_document<T>(&self, id: Uuid) -> Result<Document<T>>
+    /// Runs `hook` with every inserted document once it is persisted.
+    pub async fn after_insert<T, F>(&self, hook: F)
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + 'static,
+        F: Fn(&Document<T>) + Send + Sync + 'static,
     {
-        let mut data = self.write().await?;
-        let value = data.remove(&id).unwrap();
-        let data = self.deserialize(&value)?;
-        let doc = self.create_doc(&id, data, Status::De);
-        Ok(doc)
+        self.hooks.write().await.add_after(Status::In, hook);
     }
 
-    pub async fn delete_one<T>(&self, id: &Uuid) -> Result<Document<T>>
+    /// Same as `after_insert` for updates, with the new value.
+    pub async fn after_update<T, F>(&self, hook: F)
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + 'static,
+        F: Fn(&Document<T>) + Send + Sync + 'static,
     {
-        let result = self.remove_document(*id).await?;
-        Ok(result)
+        self.hooks.write().await.add_after(Status::Up, hook);
     }
 
-    pub async fn find_all<T>(&self) -> Result<Vec<Document<T>>>
+    /// Same as `after_insert` for deletes, with the deleted value.
+    pub async fn after_delete<T, F>(&self, hook: F)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + 'static,
+        F: Fn(&Document<T>) + Send + Sync + 'static,
+    {
+        self.hooks.write().await.add_after(Status::De, hook);
+    }
+
+    /// Checks `T::validate` before every insert and update of a `T`.
+    pub async fn validate<T>(&self)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Validate + 'static,
+    {
+        let mut hooks = self.hooks.write().await;
+        hooks.add_before(Status::In, |_id: &Uuid, value: &T| value.validate());
+        hooks.add_before(Status::Up, |_id: &Uuid, value: &T| value.validate());
+    }
+
+    fn create_doc<T>(&self, id: &Uuid, value: T, status: Status, record: &Record) -> Document<T>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
     {
-        let data = self
-            .read()
-            .await
-            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let mut doc = Document::new(*id, value, status);
+        doc._rev = record.rev;
+        doc._created = record.created;
+        doc._updated = record.updated;
+        doc._exp = record.expires;
+        doc._purge = record.purge();
+        doc._v = self.version;
+        doc._col = self.collection.to_owned();
+        doc
+    }
 
-        let docs: Vec<Document<T>> = data
-            .iter()
-            .map(|(id, data)| {
-                let data = self.deserialize(&*data).unwrap();
-                self.create_doc(id, data, Status::In)
-            })
-            .collect();
+    fn read_doc<T>(&self, id: &Uuid, record: &Record) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let value = self.deserialize(&record.value)?;
+        Ok(self.create_doc(id, value, record.status(), record))
+    }
 
-        Ok(docs)
+    /// Document persisted to delete `record`: a `De`, or with soft deletes a
+    /// `Tr` for its next revision, which is returned to be kept in the trash.
+    fn delete_doc<T>(
+        &self,
+        id: &Uuid,
+        value: T,
+        record: &Record,
+        now: u64,
+    ) -> (Document<T>, Option<Record>)
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        if !self.soft_delete {
+            return (self.create_doc(id, value, Status::De, record), None);
+        }
+        let purge = self.retention.map_or(NEVER, |retention| {
+            now.saturating_add(retention.as_millis() as u64)
+        });
+        let trashed = record.trash(now, purge);
+        (
+            self.create_doc(id, value, Status::Tr, &trashed),
+            Some(trashed),
+        )
     }
 
-    pub async fn find<T>(&self, search: &T) -> Result<Vec<Document<T>>>
+    async fn find_uuids<T>(&self, search: &T) -> Result<Vec<Uuid>>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
     {
@@ -240,257 +767,3331 @@ where
 
         let serialized = self.serialize(search)?;
 
-        let docs: Vec<Document<T>> = data
+        let docs: Vec<Uuid> = data
             .iter()
-            .filter(|(_id, data)| **data == serialized)
-            .map(|(_id, data)| {
-                let data = self.deserialize(&*data).unwrap();
-                self.create_doc(_id, data, Status::In)
-            })
+            .filter(|(_id, record)| record.value == serialized)
+            .map(|(_id, _value)| *_id)
             .collect();
 
         Ok(docs)
     }
 
-    pub async fn update<T>(&self, search: &T, new_value: &T) -> Result<usize>
+    fn uuids_where<T, F>(&self, data: &RedDbHM, predicate: &F) -> Result<Vec<Uuid>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        F: Fn(&T) -> bool,
+    {
+        let mut uuids: Vec<Uuid> = Vec::new();
+        for (id, record) in data.iter() {
+            let value: T = self.deserialize(&record.value)?;
+            if predicate(&value) {
+                uuids.push(*id);
+            }
+        }
+
+        Ok(uuids)
+    }
+
+    async fn replace_documents<T>(
+        &self,
+        data: &mut RedDbHM,
+        uuids: Vec<Uuid>,
+        new_value: &T,
+    ) -> Result<Vec<Document<T>>>
     where
         for<'de> T: Serialize + Deserialize<'de> + Clone + Debug + PartialEq + Send + Sync,
     {
-        let mut data = self
+        let serialized = self.serialize(new_value)?;
+        let now = self.now();
+        for id in uuids.iter().filter(|id| data.contains_key(id)) {
+            self.before_write(&Status::Up, id, &serialized).await?;
+        }
+        let batch: Vec<(Uuid, &[u8])> = uuids.iter().map(|id| (*id, &serialized[..])).collect();
+        self.indexes
             .write()
             .await
-            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+            .write_all(&self.serializer, data, &batch)?;
 
-        let query = self.serialize(search)?;
-
-        let docs: Vec<Document<T>> = data
-            .iter_mut()
-            .filter(|(_id, data)| **data == query)
-            .map(|(_id, data)| {
-                *data = self.serialize(new_value).unwrap();
-                self.create_doc(_id, new_value.to_owned(), Status::Up)
+        let mut previous: Vec<Option<Vec<u8>>> = Vec::new();
+        let docs: Vec<Document<T>> = uuids
+            .into_iter()
+            .filter_map(|id| {
+                let current = data.get(&id)?;
+                previous.push(Some(current.value.to_owned()));
+                let record = current.update(serialized.clone(), now);
+                let doc = self.create_doc(&id, new_value.to_owned(), Status::Up, &record);
+                data.insert(id, record);
+                Some(doc)
             })
             .collect();
 
-        let result = docs.len();
-
-        self.storage
-            .persist(&docs)
-            .await
-            .map_err(|_| RedDbErrorKind::Datapersist)?;
+        self.persist_docs(&docs, previous).await?;
 
-        Ok(result)
+        Ok(docs)
     }
 
-    pub async fn delete<T>(&self, search: &T) -> Result<usize>
+    /// Removes the documents of `uuids` that still exist and persists them.
+    /// Before hooks run for all of them first, so a rejection removes none.
+    async fn remove_documents<T>(
+        &self,
+        data: &mut RedDbHM,
+        uuids: Vec<Uuid>,
+    ) -> Result<Vec<Document<T>>>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
     {
-        let uuids = self.find_uuids(search).await?;
+        for id in uuids.iter() {
+            if let Some(record) = data.get(id) {
+                self.before_write(&Status::De, id, &record.value).await?;
+            }
+        }
 
-        let docs: Vec<Document<T>> = stream::iter(uuids)
-            .then(|_id| self.remove_document(_id))
-            .try_collect()
-            .await?;
+        let now = self.now();
+        let mut indexes = self.indexes.write().await;
+        let mut trash = self.trash.write().await;
+        let mut docs: Vec<Document<T>> = Vec::new();
+        for id in uuids {
+            if let Some(record) = data.remove(&id) {
+                indexes.remove(&id);
+                let value = self.deserialize(&record.value)?;
+                let (doc, trashed) = self.delete_doc(&id, value, &record, now);
+                trash.extend(trashed.map(|trashed| (id, trashed)));
+                docs.push(doc);
+            }
+        }
+        drop(trash);
 
-        self.storage
-            .persist(&docs)
-            .await
-            .map_err(|_| RedDbErrorKind::Datapersist)?;
+        self.persist_docs(&docs, Vec::new()).await?;
 
-        Ok(docs.len())
+        Ok(docs)
     }
 
-    fn serialize<T>(&self, value: &T) -> Result<Vec<u8>>
+    async fn insert_document<T>(&self, value: T, expires: Option<u64>) -> Result<Document<T>>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
     {
-        Ok(self
-            .serializer
-            .serialize(value)
-            .map_err(|_| RedDbErrorKind::Serialization)?)
+        let mut data = self.write().await?;
+        let now = self.now();
+        let id = Uuid::new_v4();
+        let serialized = self.serialize(&value)?;
+        self.before_write(&Status::In, &id, &serialized).await?;
+        self.indexes
+            .write()
+            .await
+            .write(&self.serializer, &data, &id, &serialized)?;
+        let record = Record {
+            expires,
+            ..Record::new(serialized, now)
+        };
+        if let Some(at) = expires {
+            self.next_expiry.fetch_min(at, Ordering::SeqCst);
+        }
+        let result = self.create_doc(&id, value, Status::default(), &record);
+        data.insert(id, record);
+
+        self.persist_docs(std::slice::from_ref(&result), Vec::new())
+            .await?;
+
+        Ok(result)
     }
 
-    fn deserialize<T>(&self, value: &[u8]) -> Result<T>
+    pub async fn insert_one<T>(&self, value: T) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + Clone + PartialEq + Send + Sync,
+    {
+        self.insert_document(value, None).await
+    }
+
+    /// Inserts `value` to expire once `ttl` has elapsed. Updates keep the
+    /// expiry, and expired documents are never returned by reads.
+    pub async fn insert_with_ttl<T>(&self, value: T, ttl: Duration) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let expires = timestamp(self.clock.now() + ttl);
+        self.insert_document(value, Some(expires)).await
+    }
+
+    /// Makes the document `id` expire at `at`, keeping its revision. It fails
+    /// with `NotFound` if the document doesn't exist or has already expired.
+    pub async fn set_expiry<T>(&self, id: &Uuid, at: SystemTime) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self.write().await?;
+        let record = data
+            .get_mut(id)
+            .ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+        let value = self.deserialize(&record.value)?;
+        let expires = timestamp(at);
+        record.expires = Some(expires);
+        self.next_expiry.fetch_min(expires, Ordering::SeqCst);
+
+        // the data doesn't change, so watchers and hooks aren't told
+        let doc = self.create_doc(id, value, Status::Up, record);
+        self.append(std::slice::from_ref(&doc)).await?;
+
+        Ok(doc)
+    }
+
+    /// Inserts `value` under a caller-supplied id, failing with `DuplicateId`
+    /// when a document with that id already exists.
+    pub async fn insert_with_id<T>(&self, id: &Uuid, value: T) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        self.put_document(id, value, false).await
+    }
+
+    /// Inserts `value` under `id` or replaces the existing document. The
+    /// returned document has `Status::In` when it was inserted and `Status::Up`
+    /// when it was replaced.
+    pub async fn upsert_one<T>(&self, id: &Uuid, value: T) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        self.put_document(id, value, true).await
+    }
+
+    async fn put_document<T>(&self, id: &Uuid, value: T, replace: bool) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let now = self.now();
+
+        let serialized = self.serialize(&value)?;
+        let previous = data.get(id).map(|current| current.value.to_owned());
+        let (record, status) = match data.get(id) {
+            Some(_) if !replace => return Err(RedDbErrorKind::DuplicateId { _id: *id }.into()),
+            Some(current) => (current.update(serialized, now), Status::Up),
+            None => (Record::new(serialized, now), Status::In),
+        };
+        self.before_write(&status, id, &record.value).await?;
+
+        self.indexes
+            .write()
+            .await
+            .write(&self.serializer, &data, id, &record.value)?;
+        let doc = self.create_doc(id, value, status, &record);
+        data.insert(*id, record);
+        // it replaces a trashed document with its id, as it does on load
+        self.trash.write().await.remove(id);
+
+        self.persist_docs(std::slice::from_ref(&doc), vec![previous])
+            .await?;
+
+        Ok(doc)
+    }
+
+    pub async fn insert<T>(&self, values: Vec<T>) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self.write().await?;
+        let now = self.now();
+
+        let mut writes: Vec<(Uuid, Vec<u8>)> = Vec::new();
+        for value in values.iter() {
+            writes.push((Uuid::new_v4(), self.serialize(value)?));
+        }
+        for (id, value) in writes.iter() {
+            self.before_write(&Status::In, id, value).await?;
+        }
+
+        let batch: Vec<(Uuid, &[u8])> = writes.iter().map(|(id, v)| (*id, &v[..])).collect();
+        self.indexes
+            .write()
+            .await
+            .write_all(&self.serializer, &data, &batch)?;
+
+        let docs: Vec<Document<T>> = writes
+            .into_iter()
+            .zip(values)
+            .map(|((id, serialized), value)| {
+                let record = Record::new(serialized, now);
+                let doc = self.create_doc(&id, value, Status::default(), &record);
+                data.insert(id, record);
+                doc
+            })
+            .collect();
+
+        self.persist_docs(&docs, Vec::new()).await?;
+
+        Ok(docs)
+    }
+
+    pub async fn find_one<T>(&self, id: &Uuid) -> Result<Document<T>>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
     {
-        Ok(self
-            .serializer
-            .deserialize(value)
-            .map_err(|_| RedDbErrorKind::Deserialization)?)
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let record = data.get(id).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+
+        let doc = self.read_doc(id, record)?;
+        Ok(doc)
     }
-}
 
-#[cfg(test)]
-#[cfg_attr(not(feature = "ron_ser"), ignore)]
-mod tests {
-    use super::*;
-    use crate::RonDb;
-    use std::fs;
+    pub async fn update_one<T>(&'a self, id: &Uuid, new_value: T) -> Result<bool>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let updated = self.update_document(id, new_value, None).await?;
+        Ok(updated.is_some())
+    }
 
-    #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
-    struct TestStruct {
-        foo: String,
+    /// Updates the document only if it is still at `expected_rev`, failing
+    /// with `Conflict` otherwise. Returns the document with its new `_rev`.
+    pub async fn update_one_if<T>(
+        &self,
+        id: &Uuid,
+        expected_rev: u64,
+        new_value: T,
+    ) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let updated = self
+            .update_document(id, new_value, Some(expected_rev))
+            .await?;
+        Ok(updated.ok_or(RedDbErrorKind::NotFound { _id: *id })?)
     }
 
-    #[tokio::test]
-    async fn insert_document() {
-        let db = RonDb::new::<TestStruct>(".test.db").unwrap();
-        let _id = &Uuid::new_v4();
-        let data = TestStruct {
-            foo: "test".to_owned(),
+    async fn update_document<T>(
+        &self,
+        id: &Uuid,
+        new_value: T,
+        expected_rev: Option<u64>,
+    ) -> Result<Option<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let current = match data.get(id) {
+            Some(current) => current,
+            None => return Ok(None),
+        };
+        if let Some(expected) = expected_rev {
+            if current.rev != expected {
+                return Err(RedDbErrorKind::Conflict {
+                    _id: *id,
+                    expected,
+                    found: current.rev,
+                }
+                .into());
+            }
+        }
+
+        Ok(Some(self.write_update(&mut data, id, new_value).await?))
+    }
+
+    /// Replaces the value of the document `id`, which must exist in `data`,
+    /// and persists the update.
+    async fn write_update<T>(&self, data: &mut RedDbHM, id: &Uuid, value: T) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let current = data.get(id).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+        let previous = current.value.to_owned();
+        let record = current.update(self.serialize(&value)?, self.now());
+        self.before_write(&Status::Up, id, &record.value).await?;
+        self.indexes
+            .write()
+            .await
+            .write(&self.serializer, data, id, &record.value)?;
+        let doc = self.create_doc(id, value, Status::Up, &record);
+        data.insert(*id, record);
+
+        self.persist_docs(std::slice::from_ref(&doc), vec![Some(previous)])
+            .await?;
+
+        Ok(doc)
+    }
+
+    /// Applies `f` to the document while holding the write lock, so no other
+    /// write can happen between reading and storing it.
+    pub async fn update_with<T, F>(&self, id: &Uuid, f: F) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        F: FnOnce(&mut T),
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let current = data.get(id).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+        let mut value: T = self.deserialize(&current.value)?;
+        f(&mut value);
+        self.write_update(&mut data, id, value).await
+    }
+
+    /// Same as `update_with` for every document matching `predicate`.
+    pub async fn update_where_with<T, P, F>(&self, predicate: P, f: F) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        P: Fn(&T) -> bool,
+        F: Fn(&mut T),
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let now = self.now();
+
+        let mut updates: Vec<(Uuid, Record, T)> = Vec::new();
+        let mut previous: Vec<Option<Vec<u8>>> = Vec::new();
+        for (id, current) in data.iter() {
+            let mut value: T = self.deserialize(&current.value)?;
+            if predicate(&value) {
+                f(&mut value);
+                previous.push(Some(current.value.to_owned()));
+                updates.push((*id, current.update(self.serialize(&value)?, now), value));
+            }
+        }
+
+        for (id, record, _value) in updates.iter() {
+            self.before_write(&Status::Up, id, &record.value).await?;
+        }
+        let batch: Vec<(Uuid, &[u8])> = updates
+            .iter()
+            .map(|(id, record, _value)| (*id, &record.value[..]))
+            .collect();
+        self.indexes
+            .write()
+            .await
+            .write_all(&self.serializer, &data, &batch)?;
+
+        let docs: Vec<Document<T>> = updates
+            .into_iter()
+            .map(|(id, record, value)| {
+                let doc = self.create_doc(&id, value, Status::Up, &record);
+                data.insert(id, record);
+                doc
+            })
+            .collect();
+
+        self.persist_docs(&docs, previous).await?;
+
+        Ok(docs)
+    }
+
+    /// Applies update operators to the document. The returned document has
+    /// `Status::Up` when it changed and `Status::In` when the update was a
+    /// no-op, in which case nothing is persisted.
+    pub async fn modify_one<T>(&self, id: &Uuid, update: &Update) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let now = self.now();
+
+        let current = data.get(id).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+        let value: T = self.deserialize(&current.value)?;
+        let value = match self.modify_value(self.value_tree(&value)?, update)? {
+            Some(value) => value,
+            None => return Ok(self.create_doc(id, value, Status::In, current)),
+        };
+        let previous = current.value.to_owned();
+        let record = current.update(self.serialize(&value)?, now);
+        self.before_write(&Status::Up, id, &record.value).await?;
+
+        self.indexes
+            .write()
+            .await
+            .write(&self.serializer, &data, id, &record.value)?;
+        let doc = self.create_doc(id, value, Status::Up, &record);
+        data.insert(*id, record);
+
+        self.persist_docs(std::slice::from_ref(&doc), vec![Some(previous)])
+            .await?;
+
+        Ok(doc)
+    }
+
+    /// Applies update operators to every document matching `query` and
+    /// returns the documents that changed. Only those are persisted.
+    pub async fn modify_where<T>(&self, query: &Query, update: &Update) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let now = self.now();
+
+        let mut updates: Vec<(Uuid, Record, T)> = Vec::new();
+        let mut previous: Vec<Option<Vec<u8>>> = Vec::new();
+        for (id, current) in data.iter() {
+            let value: T = self.deserialize(&current.value)?;
+            let tree = self.value_tree(&value)?;
+            if !query.matches(&tree) {
+                continue;
+            }
+            if let Some(value) = self.modify_value::<T>(tree, update)? {
+                previous.push(Some(current.value.to_owned()));
+                updates.push((*id, current.update(self.serialize(&value)?, now), value));
+            }
+        }
+
+        for (id, record, _value) in updates.iter() {
+            self.before_write(&Status::Up, id, &record.value).await?;
+        }
+        let batch: Vec<(Uuid, &[u8])> = updates
+            .iter()
+            .map(|(id, record, _value)| (*id, &record.value[..]))
+            .collect();
+        self.indexes
+            .write()
+            .await
+            .write_all(&self.serializer, &data, &batch)?;
+
+        let docs: Vec<Document<T>> = updates
+            .into_iter()
+            .map(|(id, record, value)| {
+                let doc = self.create_doc(&id, value, Status::Up, &record);
+                data.insert(id, record);
+                doc
+            })
+            .collect();
+
+        self.persist_docs(&docs, previous).await?;
+
+        Ok(docs)
+    }
+
+    /// New value of a document after `update`, or `None` if it didn't change.
+    fn modify_value<T>(&self, mut tree: Value, update: &Update) -> Result<Option<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        if !update.apply(&mut tree)? {
+            return Ok(None);
+        }
+        let value = serde_json::from_value(tree).map_err(|_| RedDbErrorKind::Deserialization)?;
+        Ok(Some(value))
+    }
+
+    /// Same as `delete_one`, taking the id by value.
+    pub async fn remove_document<T>(&self, id: Uuid) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        self.delete_one(&id).await
+    }
+
+    pub async fn delete_one<T>(&self, id: &Uuid) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        // persisted under the same write lock as the removal
+        let mut docs = self.delete_many(std::slice::from_ref(id)).await?;
+        Ok(docs.remove(0))
+    }
+
+    /// Deletes every document in `ids` and persists them with one append. If
+    /// any id is missing it fails with `NotFound` and nothing is deleted.
+    /// Repeated ids are deleted once.
+    pub async fn delete_many<T>(&self, ids: &[Uuid]) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let now = self.now();
+        let mut docs: Vec<Document<T>> = Vec::new();
+        let mut trashed: Vec<(Uuid, Record)> = Vec::new();
+        for id in ids {
+            if docs.iter().any(|doc| doc._id == *id) {
+                continue;
+            }
+            let record = data.get(id).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+            self.before_write(&Status::De, id, &record.value).await?;
+            let value = self.deserialize(&record.value)?;
+            let (doc, record) = self.delete_doc(id, value, record, now);
+            trashed.extend(record.map(|record| (*id, record)));
+            docs.push(doc);
+        }
+
+        let mut indexes = self.indexes.write().await;
+        for id in ids {
+            data.remove(id);
+            indexes.remove(id);
+        }
+        self.trash.write().await.extend(trashed);
+
+        self.persist_docs(&docs, Vec::new()).await?;
+
+        Ok(docs)
+    }
+
+    /// Finds every id in `ids` under one read lock. Results are in the order
+    /// of `ids`, with `NotFound` for missing documents.
+    pub async fn find_many<T>(&self, ids: &[Uuid]) -> Result<Vec<Result<Document<T>>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let docs = ids
+            .iter()
+            .map(|id| match data.get(id) {
+                Some(record) => self.read_doc(id, record),
+                None => Err(RedDbErrorKind::NotFound { _id: *id }.into()),
+            })
+            .collect();
+
+        Ok(docs)
+    }
+
+    /// Replaces the document of every id under one write lock and persists
+    /// the updated ones with one append. Results are in the order of `values`,
+    /// with `NotFound` for missing documents.
+    pub async fn update_many_by_id<T>(
+        &self,
+        values: Vec<(Uuid, T)>,
+    ) -> Result<Vec<Result<Document<T>>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Clone + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let now = self.now();
+
+        let mut staged: HashMap<Uuid, Record> = HashMap::new();
+        let mut results: Vec<Result<Document<T>>> = Vec::new();
+        let mut docs: Vec<Document<T>> = Vec::new();
+        let mut previous: Vec<Option<Vec<u8>>> = Vec::new();
+        for (id, value) in values {
+            let current = match staged.get(&id).or_else(|| data.get(&id)) {
+                Some(current) => current,
+                None => {
+                    results.push(Err(RedDbErrorKind::NotFound { _id: id }.into()));
+                    continue;
+                }
+            };
+            let record = current.update(self.serialize(&value)?, now);
+            if let Err(err) = self.before_write(&Status::Up, &id, &record.value).await {
+                results.push(Err(err));
+                continue;
+            }
+            previous.push(Some(current.value.to_owned()));
+            let doc = self.create_doc(&id, value, Status::Up, &record);
+            staged.insert(id, record);
+            results.push(Ok(doc.to_owned()));
+            docs.push(doc);
+        }
+
+        let batch: Vec<(Uuid, &[u8])> = staged
+            .iter()
+            .map(|(id, record)| (*id, &record.value[..]))
+            .collect();
+        self.indexes
+            .write()
+            .await
+            .write_all(&self.serializer, &data, &batch)?;
+        data.extend(staged);
+
+        self.persist_docs(&docs, previous).await?;
+
+        Ok(results)
+    }
+
+    /// Deletes the document of every id under one write lock and persists the
+    /// deleted ones with one append. Results are in the order of `ids`, with
+    /// `NotFound` for missing documents.
+    pub async fn delete_many_by_id<T>(&self, ids: &[Uuid]) -> Result<Vec<Result<Document<T>>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Clone + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let now = self.now();
+        let mut deleted: HashMap<Uuid, Document<T>> = HashMap::new();
+        let mut trashed: Vec<(Uuid, Record)> = Vec::new();
+        let mut results: Vec<Result<Document<T>>> = Vec::new();
+        for id in ids {
+            let record = match data.get(id) {
+                Some(record) if !deleted.contains_key(id) => record,
+                _ => {
+                    results.push(Err(RedDbErrorKind::NotFound { _id: *id }.into()));
+                    continue;
+                }
+            };
+            if let Err(err) = self.before_write(&Status::De, id, &record.value).await {
+                results.push(Err(err));
+                continue;
+            }
+            let value = self.deserialize(&record.value)?;
+            let (doc, record) = self.delete_doc(id, value, record, now);
+            trashed.extend(record.map(|record| (*id, record)));
+            results.push(Ok(doc.to_owned()));
+            deleted.insert(*id, doc);
+        }
+
+        let mut indexes = self.indexes.write().await;
+        for id in deleted.keys() {
+            data.remove(id);
+            indexes.remove(id);
+        }
+        self.trash.write().await.extend(trashed);
+
+        let docs: Vec<Document<T>> = deleted.into_values().collect();
+        self.persist_docs(&docs, Vec::new()).await?;
+
+        Ok(results)
+    }
+
+    /// Versions of the document `id` kept by the `History` of its schema,
+    /// oldest first and the current one last. It is empty for documents
+    /// that don't exist.
+    pub async fn history<T>(&self, id: &Uuid) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        self.kept_versions(&data, id).await
+    }
+
+    async fn kept_versions<T>(&self, data: &RedDbHM, id: &Uuid) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let mut kept: Vec<Record> = if self.history.keeps_past() {
+            let versions = self.versions.read().await;
+            versions.get(id).cloned().unwrap_or_default()
+        } else {
+            let trash = self.trash.read().await;
+            data.get(id)
+                .or_else(|| trash.get(id))
+                .cloned()
+                .into_iter()
+                .collect()
+        };
+        // versions written before the period of the history are dropped on
+        // the next write, or when the database is loaded
+        self.history
+            .retain(&mut kept, |record| record.updated, self.now());
+
+        kept.iter()
+            .map(|record| self.read_doc(id, record))
+            .collect()
+    }
+
+    /// Kept version of the document `id` with a revision, or the one that
+    /// was current at a time. It fails with `NotFound` if there is none.
+    pub async fn find_one_at<T>(&self, id: &Uuid, at: At) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let versions = self.history::<T>(id).await?;
+        version_at(versions, at).ok_or_else(|| RedDbErrorKind::NotFound { _id: *id }.into())
+    }
+
+    /// Updates the document `id` back to the value it had at revision `rev`,
+    /// which must still be kept.
+    pub async fn revert<T>(&self, id: &Uuid, rev: u64) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let versions = self.kept_versions::<T>(&data, id).await?;
+        let old =
+            version_at(versions, At::Rev(rev)).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+        self.write_update(&mut data, id, old.data).await
+    }
+
+    /// Brings the trashed document `id` back. It fails with `NotFound` if
+    /// the document isn't in the trash and with `DuplicateId` if another
+    /// document has taken its id since. Hooks and watchers see it as an
+    /// insert, while the returned document has the status reads report.
+    pub async fn restore<T>(&self, id: &Uuid) -> Result<Document<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self.write().await?;
+        let mut trash = self.trash.write().await;
+        let trashed = trash.get(id).ok_or(RedDbErrorKind::NotFound { _id: *id })?;
+        if data.contains_key(id) {
+            return Err(RedDbErrorKind::DuplicateId { _id: *id }.into());
+        }
+
+        let record = trashed.update(trashed.value.to_owned(), self.now());
+        self.before_write(&Status::In, id, &record.value).await?;
+        self.indexes
+            .write()
+            .await
+            .write(&self.serializer, &data, id, &record.value)?;
+        let value = self.deserialize(&record.value)?;
+        let mut doc = self.create_doc(id, value, Status::In, &record);
+        let status = record.status();
+        trash.remove(id);
+        data.insert(*id, record);
+        drop(trash);
+
+        self.persist_docs(std::slice::from_ref(&doc), Vec::new())
+            .await?;
+
+        doc._st = status;
+        Ok(doc)
+    }
+
+    /// Trashed documents, with `Status::Tr`.
+    pub async fn list_trash<T>(&self) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let trash = self.trash.read().await;
+        trash
+            .iter()
+            .map(|(id, record)| self.read_doc(id, record))
+            .collect()
+    }
+
+    /// Deletes every trashed document for good and returns how many. Hooks
+    /// and watchers have already seen them deleted, so they aren't told.
+    pub async fn empty_trash(&self) -> Result<usize> {
+        let mut trash = self.trash.write().await;
+        let ids: Vec<Uuid> = trash.keys().copied().collect();
+        self.append_deletes(&trash, &ids).await?;
+        trash.clear();
+
+        Ok(ids.len())
+    }
+
+    pub async fn find_all<T>(&self) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let docs: Result<Vec<Document<T>>> = data
+            .iter()
+            .map(|(id, record)| self.read_doc(id, record))
+            .collect();
+
+        docs
+    }
+
+    pub async fn find<T>(&self, search: &T) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let serialized = self.serialize(search)?;
+
+        let docs: Result<Vec<Document<T>>> = data
+            .iter()
+            .filter(|(_id, record)| record.value == serialized)
+            .map(|(id, record)| self.read_doc(id, record))
+            .collect();
+
+        docs
+    }
+
+    /// Streams every document, deserializing `STREAM_CHUNK_SIZE` documents
+    /// per read lock. Documents that fail to deserialize are yielded as errors.
+    pub fn find_all_stream<T>(&'a self) -> impl Stream<Item = Result<Document<T>>> + 'a
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'a,
+    {
+        self.stream_documents(None)
+    }
+
+    /// Streaming version of `find`.
+    pub fn find_stream<T>(&'a self, search: &T) -> impl Stream<Item = Result<Document<T>>> + 'a
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'a,
+    {
+        self.stream_documents(Some(self.serialize(search)))
+    }
+
+    fn stream_documents<T>(
+        &'a self,
+        search: Option<Result<Vec<u8>>>,
+    ) -> impl Stream<Item = Result<Document<T>>> + 'a
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'a,
+    {
+        stream::once(async move {
+            let search = search.transpose()?;
+            let data = self
+                .read()
+                .await
+                .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+            let uuids: Vec<Uuid> = data
+                .iter()
+                .filter(|(_id, record)| {
+                    search.as_ref().is_none_or(|search| record.value == *search)
+                })
+                .map(|(id, _data)| *id)
+                .collect();
+
+            let chunks: Vec<Vec<Uuid>> = uuids
+                .chunks(STREAM_CHUNK_SIZE)
+                .map(|chunk| chunk.to_vec())
+                .collect();
+
+            let docs = stream::iter(chunks)
+                .then(move |chunk| self.read_chunk::<T>(chunk))
+                .flat_map(stream::iter);
+            Ok::<_, RedDbError>(docs)
+        })
+        .try_flatten()
+    }
+
+    async fn read_chunk<T>(&self, uuids: Vec<Uuid>) -> Vec<Result<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let data = match self.read().await {
+            Ok(data) => data,
+            Err(_) => return vec![Err(RedDbErrorKind::PoisonedValue.into())],
+        };
+
+        // documents deleted since the stream started are skipped
+        uuids
+            .into_iter()
+            .filter_map(|id| Some(self.read_doc(&id, data.get(&id)?)))
+            .collect()
+    }
+
+    pub async fn update<T>(&self, search: &T, new_value: &T) -> Result<usize>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Clone + Debug + PartialEq + Send + Sync,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let query = self.serialize(search)?;
+
+        let uuids: Vec<Uuid> = data
+            .iter()
+            .filter(|(_id, record)| record.value == query)
+            .map(|(_id, _data)| *_id)
+            .collect();
+
+        let docs = self.replace_documents(&mut data, uuids, new_value).await?;
+
+        Ok(docs.len())
+    }
+
+    pub async fn delete<T>(&self, search: &T) -> Result<usize>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        let uuids = self.find_uuids(search).await?;
+
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let docs: Vec<Document<T>> = self.remove_documents(&mut data, uuids).await?;
+
+        Ok(docs.len())
+    }
+
+    pub async fn find_where<T, F>(&self, predicate: F) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        F: Fn(&T) -> bool,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let mut docs = Vec::new();
+        for (id, record) in data.iter() {
+            let value: T = self.deserialize(&record.value)?;
+            if predicate(&value) {
+                docs.push(self.create_doc(id, value, record.status(), record));
+            }
+        }
+
+        Ok(docs)
+    }
+
+    pub async fn count_where<T, F>(&self, predicate: F) -> Result<usize>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        F: Fn(&T) -> bool,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let mut count = 0;
+        for record in data.values() {
+            let value: T = self.deserialize(&record.value)?;
+            if predicate(&value) {
+                count += 1;
+            }
+        }
+
+        Ok(count)
+    }
+
+    pub async fn update_where<T, F>(&self, predicate: F, new_value: &T) -> Result<usize>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Clone + Debug + PartialEq + Send + Sync,
+        F: Fn(&T) -> bool,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let uuids = self.uuids_where(&data, &predicate)?;
+
+        let docs = self.replace_documents(&mut data, uuids, new_value).await?;
+
+        Ok(docs.len())
+    }
+
+    pub async fn delete_where<T, F>(&self, predicate: F) -> Result<usize>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        F: Fn(&T) -> bool,
+    {
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let uuids = self.uuids_where(&data, &predicate)?;
+
+        let docs: Vec<Document<T>> = self.remove_documents(&mut data, uuids).await?;
+
+        Ok(docs.len())
+    }
+
+    /// Runs `f` with a `Transaction` staging inserts, updates and deletes.
+    /// When `f` returns `Ok` every staged operation is applied under one
+    /// write lock and persisted as a single batch; otherwise nothing is.
+    pub async fn transaction<T, F, Fut, R>(&self, f: F) -> Result<R>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        F: FnOnce(Transaction<T>) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        let tx = Transaction::new();
+        let result = f(tx.clone()).await?;
+        self.commit(tx.take()).await?;
+        Ok(result)
+    }
+
+    async fn commit<T>(&self, ops: Vec<Op<T>>) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+    {
+        if ops.is_empty() {
+            return Ok(());
+        }
+
+        let mut data = self
+            .write()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let now = self.now();
+
+        // final content of every touched document, `None` once deleted
+        let mut staged: HashMap<Uuid, Option<Record>> = HashMap::new();
+        let mut trashed: HashMap<Uuid, Record> = HashMap::new();
+        let mut docs: Vec<Document<T>> = Vec::new();
+        let mut previous: Vec<Option<Vec<u8>>> = Vec::new();
+        for op in ops {
+            let current = |id: &Uuid| match staged.get(id) {
+                Some(record) => record.clone(),
+                None => data.get(id).cloned(),
+            };
+            match op {
+                Op::Insert(id, value) => {
+                    let record = Record::new(self.serialize(&value)?, now);
+                    self.before_write(&Status::In, &id, &record.value).await?;
+                    docs.push(self.create_doc(&id, value, Status::In, &record));
+                    previous.push(None);
+                    staged.insert(id, Some(record));
+                }
+                Op::Update(id, value) => {
+                    let current = current(&id).ok_or(RedDbErrorKind::NotFound { _id: id })?;
+                    let record = current.update(self.serialize(&value)?, now);
+                    self.before_write(&Status::Up, &id, &record.value).await?;
+                    docs.push(self.create_doc(&id, value, Status::Up, &record));
+                    previous.push(Some(current.value));
+                    staged.insert(id, Some(record));
+                }
+                Op::Delete(id) => {
+                    let record = current(&id).ok_or(RedDbErrorKind::NotFound { _id: id })?;
+                    self.before_write(&Status::De, &id, &record.value).await?;
+                    let value = self.deserialize(&record.value)?;
+                    let (doc, record) = self.delete_doc(&id, value, &record, now);
+                    trashed.extend(record.map(|record| (id, record)));
+                    docs.push(doc);
+                    previous.push(None);
+                    staged.insert(id, None);
+                }
+            }
+        }
+
+        let changes: Vec<(Uuid, Option<&[u8]>)> = staged
+            .iter()
+            .map(|(id, record)| (*id, record.as_ref().map(|record| &record.value[..])))
+            .collect();
+        let mut indexes = self.indexes.write().await;
+        indexes.apply(&self.serializer, &data, &changes)?;
+
+        let frame = Frame {
+            id: Uuid::new_v4(),
+            len: docs.len(),
+        };
+        for doc in docs.iter_mut() {
+            doc._tx = Some(frame);
+        }
+        if self.storage.persist(&docs).await.is_err() {
+            let ids: Vec<Uuid> = staged.keys().copied().collect();
+            indexes.restore(&self.serializer, &data, &ids);
+            return Err(RedDbErrorKind::Datapersist.into());
+        }
+
+        for (id, record) in staged {
+            match record {
+                Some(record) => data.insert(id, record),
+                None => data.remove(&id),
+            };
+        }
+        self.keep_versions(&docs).await?;
+        // only documents deleted by the end of the transaction are trashed
+        let mut trash = self.trash.write().await;
+        trash.extend(trashed.into_iter().filter(|(id, _)| !data.contains_key(id)));
+        drop(trash);
+        self.publish(&docs, previous).await;
+
+        Ok(())
+    }
+
+    pub async fn count(&self) -> Result<usize> {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        Ok(data.len())
+    }
+
+    pub async fn exists<T, F>(&self, predicate: F) -> Result<bool>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        F: Fn(&T) -> bool,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        for record in data.values() {
+            let value: T = self.deserialize(&record.value)?;
+            if predicate(&value) {
+                return Ok(true);
+            }
+        }
+
+        Ok(false)
+    }
+
+    /// Distinct values of the field at `field`, most frequent first.
+    pub async fn distinct<T>(&self, field: &str) -> Result<Vec<Value>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let facets = self.facets::<T>(field).await?;
+        Ok(facets.into_iter().map(|(value, _count)| value).collect())
+    }
+
+    /// Number of documents per value of the field at `field`, most frequent
+    /// first. Array fields count every element. When an index was created
+    /// over the field with `create_field_index` or `create_unique_field` its
+    /// keys are used instead of scanning the documents.
+    pub async fn facets<T>(&self, field: &str) -> Result<Vec<(Value, usize)>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        if let Some(counts) = self.indexes.read().await.field_counts(field) {
+            return Ok(aggregate::tally_counts(counts));
+        }
+
+        let mut values = Vec::new();
+        for record in data.values() {
+            let value: T = self.deserialize(&record.value)?;
+            if let Some(value) = query::lookup(&self.value_tree(&value)?, field) {
+                values.push(value.to_owned());
+            }
+        }
+
+        Ok(aggregate::tally(values))
+    }
+
+    /// Groups documents by `key_fn` and computes count, sum, min, max and avg
+    /// of `value_fn` for every group.
+    pub async fn group_by<T, K, FK, FV>(
+        &self,
+        key_fn: FK,
+        value_fn: FV,
+    ) -> Result<HashMap<K, Stats>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        K: Hash + Eq,
+        FK: Fn(&T) -> K,
+        FV: Fn(&T) -> f64,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let mut groups: HashMap<K, Stats> = HashMap::new();
+        for record in data.values() {
+            let value: T = self.deserialize(&record.value)?;
+            let amount = value_fn(&value);
+            groups
+                .entry(key_fn(&value))
+                .and_modify(|stats| stats.add(amount))
+                .or_insert_with(|| Stats::new(amount));
+        }
+
+        Ok(groups)
+    }
+
+    /// Same as `group_by`, grouping by the keys of the hash index `name`
+    /// instead of calling a key function on every document. Documents left
+    /// out of the index are left out of the groups.
+    pub async fn group_by_index<T, K, FV>(
+        &self,
+        name: &str,
+        value_fn: FV,
+    ) -> Result<HashMap<K, Stats>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Hash + Eq + Clone + 'static,
+        FV: Fn(&T) -> f64,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+        let indexes = self.indexes.read().await;
+        let index = indexes.get::<HashIndex<T, K>>(name)?;
+
+        let mut groups: HashMap<K, Stats> = HashMap::new();
+        for (key, ids) in index.groups() {
+            for record in ids.iter().filter_map(|id| data.get(id)) {
+                let value: T = self.deserialize(&record.value)?;
+                let amount = value_fn(&value);
+                groups
+                    .entry(key.clone())
+                    .and_modify(|stats| stats.add(amount))
+                    .or_insert_with(|| Stats::new(amount));
+            }
+        }
+
+        Ok(groups)
+    }
+
+    /// Hash index keyed by `extractor`, built from the loaded documents.
+    pub async fn create_index<T, K, F>(&self, name: &'static str, extractor: F) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Serialize + Hash + Eq + Clone + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let index = HashIndex::new(
+            name,
+            false,
+            move |value: &T| Some(extractor(value)),
+            |key: &K| serde_json::to_value(key).ok(),
+        );
+        self.add_index(&data, name, index).await
+    }
+
+    /// Same as `create_index`, rejecting writes that would duplicate a key.
+    pub async fn create_unique_index<T, K, F>(&self, name: &'static str, extractor: F) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Serialize + Hash + Eq + Clone + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let index = HashIndex::new(
+            name,
+            true,
+            move |value: &T| Some(extractor(value)),
+            |key: &K| serde_json::to_value(key).ok(),
+        );
+        self.add_index(&data, name, index).await
+    }
+
+    /// Hash index over the field at `path`, named after the field. Index keys
+    /// are the field values encoded as JSON.
+    pub async fn create_field_index<T>(&self, path: &'static str) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+    {
+        self.add_field_index::<T>(path, false).await
+    }
+
+    /// Unique index over the field at `path`. Documents without the field are
+    /// not constrained. Index keys are the field values encoded as JSON.
+    pub async fn create_unique_field<T>(&self, path: &'static str) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+    {
+        self.add_field_index::<T>(path, true).await
+    }
+
+    async fn add_field_index<T>(&self, path: &'static str, unique: bool) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let index = HashIndex::<T, String>::for_field(path, unique);
+        self.add_index(&data, path, index).await?;
+        self.storage
+            .save_index(&IndexDef {
+                collection: self.collection.to_owned(),
+                name: path.to_owned(),
+                kind: IndexKind::Field { unique },
+            })
+            .await
+    }
+
+    /// Ordered index keyed by `extractor`, for `find_range`.
+    pub async fn create_range_index<T, K, F>(&self, name: &'static str, extractor: F) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Serialize + Ord + Clone + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let index = OrderedIndex::new(
+            move |value: &T| Some(extractor(value)),
+            |key: &K| serde_json::to_value(key).ok(),
+        );
+        self.add_index(&data, name, index).await
+    }
+
+    /// Full-text index over the string fields at `fields`. Arrays of strings
+    /// are indexed element by element.
+    pub async fn create_text_index<T>(&self, name: &'static str, fields: &[&str]) -> Result<()>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let fields: Vec<String> = fields.iter().map(|field| field.to_string()).collect();
+        let index = TextIndex::<T>::for_fields(fields.clone());
+        self.add_index(&data, name, index).await?;
+        self.storage
+            .save_index(&IndexDef {
+                collection: self.collection.to_owned(),
+                name: name.to_owned(),
+                kind: IndexKind::Text { fields },
+            })
+            .await
+    }
+
+    async fn add_index<I>(&self, data: &RedDbHM, name: &'static str, mut index: I) -> Result<()>
+    where
+        I: Indexer<SE> + 'static,
+    {
+        index.rebuild(&self.serializer, data)?;
+        self.indexes.write().await.add(name, Box::new(index));
+        Ok(())
+    }
+
+    pub async fn find_by_index<T, K>(&self, name: &str, key: &K) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let indexes = self.indexes.read().await;
+        let uuids = indexes.get::<HashIndex<T, K>>(name)?.find(key);
+
+        let mut docs = Vec::new();
+        for id in uuids {
+            if let Some(record) = data.get(&id) {
+                docs.push(self.read_doc(&id, record)?);
+            }
+        }
+
+        Ok(docs)
+    }
+
+    pub async fn find_range<T, K, R>(&self, name: &str, range: R) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        R: RangeBounds<K>,
+    {
+        self.range_documents(name, range, false).await
+    }
+
+    pub async fn find_range_rev<T, K, R>(&self, name: &str, range: R) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        R: RangeBounds<K>,
+    {
+        self.range_documents(name, range, true).await
+    }
+
+    async fn range_documents<T, K, R>(
+        &self,
+        name: &str,
+        range: R,
+        reverse: bool,
+    ) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+        K: Ord + Clone + Send + Sync + 'static,
+        R: RangeBounds<K>,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let indexes = self.indexes.read().await;
+        let uuids = indexes
+            .get::<OrderedIndex<T, K>>(name)?
+            .range(range, reverse);
+
+        let mut docs = Vec::new();
+        for id in uuids {
+            if let Some(record) = data.get(&id) {
+                docs.push(self.read_doc(&id, record)?);
+            }
+        }
+
+        Ok(docs)
+    }
+
+    /// Ranked full-text search over a text index, best match first. Terms
+    /// ending with `*` match as prefix, terms ending with `~` match fuzzily.
+    pub async fn search<T>(&self, name: &str, text: &str) -> Result<Vec<(Document<T>, f64)>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + 'static,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let indexes = self.indexes.read().await;
+        let hits = indexes.get::<TextIndex<T>>(name)?.search(text);
+
+        let mut docs = Vec::new();
+        for (id, score) in hits {
+            if let Some(record) = data.get(&id) {
+                docs.push((self.read_doc(&id, record)?, score));
+            }
+        }
+
+        Ok(docs)
+    }
+
+    pub async fn find_query<T>(&self, query: &Query) -> Result<Vec<Document<T>>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let mut docs = Vec::new();
+        for (id, record) in data.iter() {
+            let value: T = self.deserialize(&record.value)?;
+            if query.matches(&self.value_tree(&value)?) {
+                docs.push(self.create_doc(id, value, record.status(), record));
+            }
+        }
+
+        Ok(docs)
+    }
+
+    pub async fn find_all_with<T>(&self, options: &FindOptions) -> Result<Page<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        self.find_page(options, |_value: &T| Ok(true)).await
+    }
+
+    pub async fn find_where_with<T, F>(
+        &self,
+        predicate: F,
+        options: &FindOptions,
+    ) -> Result<Page<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        F: Fn(&T) -> bool,
+    {
+        self.find_page(options, |value: &T| Ok(predicate(value)))
+            .await
+    }
+
+    pub async fn find_query_with<T>(&self, query: &Query, options: &FindOptions) -> Result<Page<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        self.find_page(options, |value: &T| {
+            Ok(query.matches(&self.value_tree(value)?))
+        })
+        .await
+    }
+
+    async fn find_page<T, F>(&self, options: &FindOptions, predicate: F) -> Result<Page<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+        F: Fn(&T) -> Result<bool>,
+    {
+        let data = self
+            .read()
+            .await
+            .map_err(|_| RedDbErrorKind::PoisonedValue)?;
+
+        let mut keys = Vec::new();
+        for (id, record) in data.iter() {
+            let value: T = self.deserialize(&record.value)?;
+            if !predicate(&value)? {
+                continue;
+            }
+            let key = match options.sort_by {
+                Some(_) => options.sort_key(id, Some(&self.value_tree(&value)?)),
+                None => options.sort_key(id, None),
+            };
+            keys.push(key);
+        }
+
+        let (uuids, next_cursor) = options.paginate(keys)?;
+
+        let mut docs = Vec::new();
+        for id in uuids {
+            if let Some(record) = data.get(&id) {
+                docs.push(self.read_doc(&id, record)?);
+            }
+        }
+
+        Ok(Page { docs, next_cursor })
+    }
+
+    fn value_tree<T>(&self, value: &T) -> Result<Value>
+    where
+        T: Serialize,
+    {
+        Ok(serde_json::to_value(value).map_err(|_| RedDbErrorKind::Serialization)?)
+    }
+
+    fn serialize<T>(&self, value: &T) -> Result<Vec<u8>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        Ok(self
+            .serializer
+            .serialize(value)
+            .map_err(|_| RedDbErrorKind::Serialization)?)
+    }
+
+    fn deserialize<T>(&self, value: &[u8]) -> Result<T>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
+    {
+        Ok(self
+            .serializer
+            .deserialize(value)
+            .map_err(|_| RedDbErrorKind::Deserialization)?)
+    }
+}
+
+#[cfg(test)]
+#[cfg_attr(not(feature = "ron_ser"), ignore)]
+mod tests {
+    use super::*;
+    use crate::RonDb;
+    use std::fs;
+
+    #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+    struct TestStruct {
+        foo: String,
+    }
+
+    #[derive(Debug, Clone)]
+    struct TestClock(Arc<std::sync::Mutex<SystemTime>>);
+
+    impl TestClock {
+        fn advance(&self, by: Duration) {
+            *self.0.lock().unwrap() += by;
+        }
+    }
+
+    impl Clock for TestClock {
+        fn now(&self) -> SystemTime {
+            *self.0.lock().unwrap()
+        }
+    }
+
+    #[tokio::test]
+    async fn insert_document() {
+        let db = RonDb::new::<TestStruct>(".test.db").unwrap();
+        let _id = &Uuid::new_v4();
+        let data = TestStruct {
+            foo: "test".to_owned(),
+        };
+        let doc: Document<TestStruct> = db.insert_document(data, None).await.unwrap();
+        let find: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
+        assert_eq!(find.data, doc.data);
+    }
+    #[tokio::test]
+    async fn find_uuids() {
+        let db = RonDb::new::<TestStruct>(".test.db").unwrap();
+        let doc: Document<TestStruct> = db
+            .insert_document(
+                TestStruct {
+                    foo: "test".to_owned(),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let doc2: Document<TestStruct> = db
+            .insert_document(
+                TestStruct {
+                    foo: "test2".to_owned(),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+
+        let doc3: Document<TestStruct> = db
+            .insert_document(
+                TestStruct {
+                    foo: "test".to_owned(),
+                },
+                None,
+            )
+            .await
+            .unwrap();
+        let uuids: Vec<Uuid> = db
+            .find_uuids(&TestStruct {
+                foo: "test".to_owned(),
+            })
+            .await
+            .unwrap();
+
+        assert_eq!(uuids.contains(&doc._id), true);
+        assert_eq!(uuids.contains(&doc2._id), false);
+        assert_eq!(uuids.contains(&doc3._id), true);
+
+        fs::remove_file(".test.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn insert_and_find_one() {
+        let db = RonDb::new::<TestStruct>(".insert_and_find_one.db").unwrap();
+        let doc: Document<TestStruct> = db
+            .insert_one(TestStruct {
+                foo: "test".to_owned(),
+            })
+            .await
+            .unwrap();
+
+        let find: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
+        assert_eq!(find._id, doc._id);
+        assert_eq!(find.data, doc.data);
+
+        fs::remove_file(".insert_and_find_one.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn insert_with_id_and_upsert_one() {
+        let db = RonDb::new::<TestStruct>(".upsert_one.db").unwrap();
+        let id = Uuid::new_v4();
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        let two = TestStruct {
+            foo: "two".to_owned(),
+        };
+
+        let doc = db.insert_with_id(&id, one.clone()).await.unwrap();
+        assert_eq!((doc._id, doc._st), (id, Status::In));
+        let err = db.insert_with_id(&id, two.clone()).await.unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::DuplicateId { _id: id });
+
+        let doc = db.upsert_one(&id, two.clone()).await.unwrap();
+        assert_eq!((doc._st, doc._rev), (Status::Up, 2));
+        let other = Uuid::new_v4();
+        let doc = db.upsert_one(&other, one.clone()).await.unwrap();
+        assert_eq!((doc._st, doc._rev), (Status::In, 1));
+
+        let db = RonDb::new::<TestStruct>(".upsert_one.db").unwrap();
+        let found: Document<TestStruct> = db.find_one(&id).await.unwrap();
+        assert_eq!((found.data, found._rev), (two, 2));
+        let found: Document<TestStruct> = db.find_one(&other).await.unwrap();
+        assert_eq!(found.data, one);
+        fs::remove_file(".upsert_one.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn find() {
+        let db = RonDb::new::<TestStruct>(".find.db").unwrap();
+
+        let one = TestStruct {
+            foo: String::from("one"),
+        };
+
+        let two = TestStruct {
+            foo: String::from("two"),
+        };
+
+        let many = vec![one.clone(), one.clone(), two.clone()];
+        db.insert(many).await.unwrap();
+        let result = db.find(&one).await.unwrap();
+        assert_eq!(result.len(), 2);
+        fs::remove_file(".find.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn update_one() {
+        let db = RonDb::new::<TestStruct>(".update_one.db").unwrap();
+        let original = TestStruct {
+            foo: "hi".to_owned(),
+        };
+
+        let updated = TestStruct {
+            foo: "bye".to_owned(),
+        };
+
+        let doc = db.insert_one(original.clone()).await.unwrap();
+        db.update_one(&doc._id, updated.clone()).await.unwrap();
+        let result: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
+        assert_eq!(result.data, updated);
+        fs::remove_file(".update_one.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn update_one_if() {
+        let db = RonDb::new::<TestStruct>(".update_one_if.db").unwrap();
+        let doc = db
+            .insert_one(TestStruct {
+                foo: "hi".to_owned(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(doc._rev, 1);
+
+        let updated = db
+            .update_one_if(
+                &doc._id,
+                doc._rev,
+                TestStruct {
+                    foo: "bye".to_owned(),
+                },
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated._rev, 2);
+
+        let err = db
+            .update_one_if(
+                &doc._id,
+                doc._rev,
+                TestStruct {
+                    foo: "stale".to_owned(),
+                },
+            )
+            .await
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            RedDbErrorKind::Conflict {
+                _id: doc._id,
+                expected: 1,
+                found: 2
+            }
+        );
+
+        let db = RonDb::new::<TestStruct>(".update_one_if.db").unwrap();
+        let found: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
+        assert_eq!(found.data.foo, "bye");
+        assert_eq!(found._rev, 2);
+        fs::remove_file(".update_one_if.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn update_with() {
+        let db = RonDb::new::<TestStruct>(".update_with.db").unwrap();
+        let doc = db
+            .insert_one(TestStruct { foo: String::new() })
+            .await
+            .unwrap();
+
+        let updates =
+            (0..20).map(|_| db.update_with(&doc._id, |t: &mut TestStruct| t.foo.push('x')));
+        futures::future::try_join_all(updates).await.unwrap();
+        let found: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
+        assert_eq!(found.data.foo.len(), 20);
+        assert_eq!(found._rev, 21);
+
+        db.insert_one(TestStruct {
+            foo: "y".to_owned(),
+        })
+        .await
+        .unwrap();
+        let updated = db
+            .update_where_with(
+                |t: &TestStruct| t.foo.len() < 20,
+                |t: &mut TestStruct| t.foo.push('y'),
+            )
+            .await
+            .unwrap();
+        assert_eq!(updated.len(), 1);
+        assert_eq!(updated[0].data.foo, "yy");
+
+        let missing = Uuid::new_v4();
+        let err = db
+            .update_with(&missing, |t: &mut TestStruct| t.foo.clear())
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::NotFound { _id: missing });
+        fs::remove_file(".update_with.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn modify_one_and_modify_where() {
+        #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+        struct Counter {
+            name: String,
+            visits: i64,
+            tags: Vec<String>,
+        }
+
+        let db = RonDb::new::<Counter>(".modify.db").unwrap();
+        let docs = db
+            .insert(vec![
+                Counter {
+                    name: "a".to_owned(),
+                    visits: 0,
+                    tags: vec![],
+                },
+                Counter {
+                    name: "b".to_owned(),
+                    visits: 5,
+                    tags: vec!["x".to_owned()],
+                },
+            ])
+            .await
+            .unwrap();
+        let lines = || {
+            fs::read_to_string(".modify.db.ron")
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        let doc: Document<Counter> = db
+            .modify_one(&docs[0]._id, &Update::new().inc("visits", 1))
+            .await
+            .unwrap();
+        assert_eq!((doc.data.visits, doc._st, doc._rev), (1, Status::Up, 2));
+        assert_eq!(lines(), 3);
+
+        let doc: Document<Counter> = db
+            .modify_one(&docs[0]._id, &Update::new().set("name", "a"))
+            .await
+            .unwrap();
+        assert_eq!((doc._st, doc._rev), (Status::In, 2));
+        assert_eq!(lines(), 3);
+
+        // reads report the status of the stored revision
+        let found: Vec<Document<Counter>> =
+            db.find_query(&Query::field("visits").eq(1)).await.unwrap();
+        assert_eq!((found[0]._st.clone(), found[0]._rev), (Status::Up, 2));
+        let found = db
+            .find_where(|counter: &Counter| counter.visits == 1)
+            .await
+            .unwrap();
+        assert_eq!(found[0]._st, Status::Up);
+
+        let query = Query::field("visits").gte(0);
+        let update = Update::new().add_to_set("tags", "x");
+        let changed: Vec<Document<Counter>> = db.modify_where(&query, &update).await.unwrap();
+        assert_eq!(changed.len(), 1);
+        assert_eq!(changed[0].data.tags, vec!["x"]);
+        assert_eq!(lines(), 4);
+
+        let err = db
+            .modify_one::<Counter>(&docs[1]._id, &Update::new().set("visits", "many"))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::Deserialization);
+        fs::remove_file(".modify.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn update() {
+        let db = RonDb::new::<TestStruct>(".update.db").unwrap();
+        let one = TestStruct {
+            foo: String::from("one"),
+        };
+        let two = TestStruct {
+            foo: String::from("two"),
+        };
+
+        let many = vec![one.clone(), one.clone(), two.clone()];
+        db.insert(many).await.unwrap();
+        let updated = db.update(&one, &two).await.unwrap();
+        assert_eq!(updated, 2);
+        let result = db.find(&two).await.unwrap();
+        assert_eq!(result.len(), 3);
+        fs::remove_file(".update.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_and_find_one() {
+        let db = RonDb::new::<TestStruct>(".delete_one.db").unwrap();
+        let search = TestStruct {
+            foo: "test".to_owned(),
+        };
+
+        let doc = db.insert_one(search.clone()).await.unwrap();
+        let deleted = db.delete_one(&doc._id).await.unwrap();
+        assert_eq!(
+            deleted,
+            Document {
+                _id: doc._id,
+                data: doc.data,
+                _st: Status::De,
+                _rev: 1,
+                _created: doc._created,
+                _updated: doc._updated,
+                _exp: None,
+                _purge: None,
+                _v: 1,
+                _col: String::new(),
+                _tx: None,
+            }
+        );
+        fs::remove_file(".delete_one.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn delete_one_and_delete_many() {
+        let db = RonDb::new::<TestStruct>(".delete_many.db").unwrap();
+        let values = vec!["a", "b", "c", "d", "e"]
+            .into_iter()
+            .map(|foo| TestStruct {
+                foo: foo.to_owned(),
+            })
+            .collect();
+        let docs = db.insert(values).await.unwrap();
+
+        db.delete_one::<TestStruct>(&docs[0]._id).await.unwrap();
+        let err = db.delete_one::<TestStruct>(&docs[0]._id).await.unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::NotFound { _id: docs[0]._id });
+
+        let err = db
+            .delete_many::<TestStruct>(&[docs[1]._id, docs[0]._id])
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::NotFound { _id: docs[0]._id });
+        assert_eq!(db.count().await.unwrap(), 4);
+
+        let deleted = db
+            .delete_many::<TestStruct>(&[docs[1]._id, docs[2]._id, docs[1]._id])
+            .await
+            .unwrap();
+        assert_eq!(deleted.len(), 2);
+        db.remove_document::<TestStruct>(docs[4]._id).await.unwrap();
+        drop(db);
+
+        let db = RonDb::new::<TestStruct>(".delete_many.db").unwrap();
+        let all: Vec<Document<TestStruct>> = db.find_all().await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0]._id, docs[3]._id);
+        fs::remove_file(".delete_many.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn many_by_id() {
+        let db = RonDb::new::<TestStruct>(".many_by_id.db").unwrap();
+        let values = vec!["a", "b", "c"]
+            .into_iter()
+            .map(|foo| TestStruct {
+                foo: foo.to_owned(),
+            })
+            .collect();
+        let docs = db.insert(values).await.unwrap();
+        let missing = Uuid::new_v4();
+        let lines = || {
+            fs::read_to_string(".many_by_id.db.ron")
+                .unwrap()
+                .lines()
+                .count()
+        };
+
+        let found: Vec<Result<Document<TestStruct>>> =
+            db.find_many(&[docs[2]._id, missing]).await.unwrap();
+        assert_eq!(found[0].as_ref().unwrap().data.foo, "c");
+        assert_eq!(
+            found[1].as_ref().unwrap_err().kind(),
+            RedDbErrorKind::NotFound { _id: missing }
+        );
+
+        let updated = db
+            .update_many_by_id(vec![
+                (
+                    docs[0]._id,
+                    TestStruct {
+                        foo: "x".to_owned(),
+                    },
+                ),
+                (
+                    missing,
+                    TestStruct {
+                        foo: "y".to_owned(),
+                    },
+                ),
+                (
+                    docs[1]._id,
+                    TestStruct {
+                        foo: "z".to_owned(),
+                    },
+                ),
+            ])
+            .await
+            .unwrap();
+        assert!(updated[0].is_ok() && updated[1].is_err() && updated[2].is_ok());
+        assert_eq!(lines(), 5);
+
+        let deleted = db
+            .delete_many_by_id::<TestStruct>(&[docs[0]._id, missing, docs[0]._id])
+            .await
+            .unwrap();
+        assert_eq!(deleted[0].as_ref().unwrap().data.foo, "x");
+        assert!(deleted[1].is_err() && deleted[2].is_err());
+        assert_eq!(lines(), 6);
+
+        let db = RonDb::new::<TestStruct>(".many_by_id.db").unwrap();
+        let found: Vec<Result<Document<TestStruct>>> =
+            db.find_many(&[docs[0]._id, docs[1]._id]).await.unwrap();
+        assert!(found[0].is_err());
+        assert_eq!(found[1].as_ref().unwrap().data.foo, "z");
+        fs::remove_file(".many_by_id.db.ron").unwrap();
+    }
+
+    async fn delete() {
+        let db = RonDb::new::<TestStruct>(".delete.db").unwrap();
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+
+        let two = TestStruct {
+            foo: "two".to_owned(),
+        };
+
+        let many = vec![one.clone(), one.clone(), two.clone()];
+        db.insert(many).await.unwrap();
+        let deleted = db.delete(&one).await.unwrap();
+        assert_eq!(deleted, 2);
+
+        let not_deleted = db.delete(&one).await.unwrap();
+        assert_eq!(not_deleted, 0);
+        fs::remove_file(".delete.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn find_where_and_count_where() {
+        let db = RonDb::new::<TestStruct>(".find_where.db").unwrap();
+        let many = vec![
+            TestStruct {
+                foo: "one".to_owned(),
+            },
+            TestStruct {
+                foo: "two".to_owned(),
+            },
+            TestStruct {
+                foo: "three".to_owned(),
+            },
+        ];
+        db.insert(many).await.unwrap();
+
+        let result: Vec<Document<TestStruct>> = db
+            .find_where(|t: &TestStruct| t.foo.starts_with('t'))
+            .await
+            .unwrap();
+        assert_eq!(result.len(), 2);
+
+        let count = db
+            .count_where(|t: &TestStruct| t.foo.len() == 3)
+            .await
+            .unwrap();
+        assert_eq!(count, 2);
+        fs::remove_file(".find_where.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn update_where_and_delete_where() {
+        let db = RonDb::new::<TestStruct>(".update_where.db").unwrap();
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        let two = TestStruct {
+            foo: "two".to_owned(),
+        };
+        let updated = TestStruct {
+            foo: "updated".to_owned(),
+        };
+
+        let many = vec![one.clone(), one.clone(), two.clone()];
+        db.insert(many).await.unwrap();
+
+        let num_updated = db
+            .update_where(|t: &TestStruct| t.foo == "one", &updated)
+            .await
+            .unwrap();
+        assert_eq!(num_updated, 2);
+        assert_eq!(db.find(&updated).await.unwrap().len(), 2);
+
+        let num_deleted = db
+            .delete_where(|t: &TestStruct| t.foo != "two")
+            .await
+            .unwrap();
+        assert_eq!(num_deleted, 2);
+        let remaining: Vec<Document<TestStruct>> = db.find_all().await.unwrap();
+        assert_eq!(remaining.len(), 1);
+        assert_eq!(remaining[0].data, two);
+        fs::remove_file(".update_where.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn transaction() {
+        let db = RonDb::new::<TestStruct>(".transaction.db").unwrap();
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        let two = TestStruct {
+            foo: "two".to_owned(),
+        };
+        let three = TestStruct {
+            foo: "three".to_owned(),
+        };
+        let docs = db.insert(vec![one.clone(), two.clone()]).await.unwrap();
+
+        let (first, second) = (docs[0]._id, docs[1]._id);
+        let inserted = db
+            .transaction(|tx| async move {
+                let id = tx.insert_one(three.clone());
+                tx.update_one(&first, two.clone());
+                tx.delete_one(&second);
+                Ok(id)
+            })
+            .await
+            .unwrap();
+
+        let check = |db: RonDb| async move {
+            let found: Document<TestStruct> = db.find_one(&inserted).await.unwrap();
+            assert_eq!(found.data.foo, "three");
+            let found: Document<TestStruct> = db.find_one(&first).await.unwrap();
+            assert_eq!(found.data.foo, "two");
+            assert!(db.find_one::<TestStruct>(&second).await.is_err());
+        };
+        check(db).await;
+        check(RonDb::new::<TestStruct>(".transaction.db").unwrap()).await;
+        fs::remove_file(".transaction.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn transaction_rollback() {
+        let db = RonDb::new::<TestStruct>(".transaction_rollback.db").unwrap();
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        let id = db.insert_one(one.clone()).await.unwrap()._id;
+        let persisted = fs::read_to_string(".transaction_rollback.db.ron").unwrap();
+
+        let err = db
+            .transaction(|tx| async move {
+                tx.insert_one(TestStruct {
+                    foo: "two".to_owned(),
+                });
+                tx.delete_one(&id);
+                Err::<(), _>(RedDbErrorKind::InvalidQuery.into())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::InvalidQuery);
+
+        let missing = Uuid::new_v4();
+        let err = db
+            .transaction(|tx: Transaction<TestStruct>| async move {
+                tx.delete_one(&id);
+                tx.update_one(&missing, one);
+                Ok(())
+            })
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::NotFound { _id: missing });
+
+        assert_eq!(db.count().await.unwrap(), 1);
+        assert_eq!(
+            fs::read_to_string(".transaction_rollback.db.ron").unwrap(),
+            persisted
+        );
+        fs::remove_file(".transaction_rollback.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn load_ignores_partial_transaction() {
+        let db = RonDb::new::<TestStruct>(".partial_transaction.db").unwrap();
+        let doc = db
+            .insert_one(TestStruct {
+                foo: "one".to_owned(),
+            })
+            .await
+            .unwrap();
+        db.transaction(|tx| async move {
+            tx.update_one(
+                &doc._id,
+                TestStruct {
+                    foo: "two".to_owned(),
+                },
+            );
+            tx.insert_one(TestStruct {
+                foo: "three".to_owned(),
+            });
+            Ok(())
+        })
+        .await
+        .unwrap();
+
+        // simulate a crash in the middle of appending the transaction
+        let persisted = fs::read_to_string(".partial_transaction.db.ron").unwrap();
+        let lines: Vec<&str> = persisted.lines().collect();
+        let torn = format!("{}\n{}", lines[..2].join("\n"), &lines[2][..10]);
+        fs::write(".partial_transaction.db.ron", torn).unwrap();
+
+        let db = RonDb::new::<TestStruct>(".partial_transaction.db").unwrap();
+        let all: Vec<Document<TestStruct>> = db.find_all().await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(all[0].data.foo, "one");
+        fs::remove_file(".partial_transaction.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn find_query() {
+        let db = RonDb::new::<TestStruct>(".find_query.db").unwrap();
+        let many = vec![
+            TestStruct {
+                foo: "one".to_owned(),
+            },
+            TestStruct {
+                foo: "two".to_owned(),
+            },
+            TestStruct {
+                foo: "three".to_owned(),
+            },
+        ];
+        db.insert(many).await.unwrap();
+
+        let query = Query::field("foo")
+            .regex("^t")
+            .unwrap()
+            .and(!Query::field("foo").eq("two"));
+        let result: Vec<Document<TestStruct>> = db.find_query(&query).await.unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].data.foo, "three");
+
+        let query = Query::from_json(r#"{"in":{"field":"foo","values":["one","two"]}}"#).unwrap();
+        let result: Vec<Document<TestStruct>> = db.find_query(&query).await.unwrap();
+        assert_eq!(result.len(), 2);
+        fs::remove_file(".find_query.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn find_by_index() {
+        let db = RonDb::new::<TestStruct>(".find_by_index.db").unwrap();
+        db.create_index("foo", |t: &TestStruct| t.foo.clone())
+            .await
+            .unwrap();
+
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        let two = TestStruct {
+            foo: "two".to_owned(),
+        };
+        let docs = db
+            .insert(vec![one.clone(), one.clone(), two.clone()])
+            .await
+            .unwrap();
+
+        let result: Vec<Document<TestStruct>> =
+            db.find_by_index("foo", &"one".to_owned()).await.unwrap();
+        assert_eq!(result.len(), 2);
+
+        db.update_one(&docs[2]._id, one.clone()).await.unwrap();
+        let result: Vec<Document<TestStruct>> =
+            db.find_by_index("foo", &"one".to_owned()).await.unwrap();
+        assert_eq!(result.len(), 3);
+
+        db.delete_one::<TestStruct>(&docs[0]._id).await.unwrap();
+        let result: Vec<Document<TestStruct>> =
+            db.find_by_index("foo", &"one".to_owned()).await.unwrap();
+        assert_eq!(result.len(), 2);
+
+        let missing = db
+            .find_by_index::<TestStruct, String>("bar", &"one".to_owned())
+            .await
+            .unwrap_err();
+        assert_eq!(missing.kind(), RedDbErrorKind::IndexNotFound);
+        fs::remove_file(".find_by_index.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn field_indexes_rebuilt_on_load() {
+        let db = RonDb::new::<TestStruct>(".rebuilt_indexes.db").unwrap();
+        db.create_unique_field::<TestStruct>("foo").await.unwrap();
+        db.create_index("len", |t: &TestStruct| t.foo.len())
+            .await
+            .unwrap();
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        db.insert_one(one.clone()).await.unwrap();
+        let notes = db.collection::<TestStruct>("notes").await.unwrap();
+        notes.create_field_index::<TestStruct>("foo").await.unwrap();
+        notes.insert_one(one.clone()).await.unwrap();
+        drop(notes);
+        drop(db);
+
+        let db = RonDb::new::<TestStruct>(".rebuilt_indexes.db").unwrap();
+        let key = "\"one\"".to_owned();
+        let found = db
+            .find_by_index::<TestStruct, String>("foo", &key)
+            .await
+            .unwrap();
+        assert_eq!(found[0].data, one);
+        let err = db.insert_one(one.clone()).await.unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            RedDbErrorKind::UniqueViolation { index: "foo", .. }
+        ));
+        // closures can't be saved
+        let err = db
+            .find_by_index::<TestStruct, usize>("len", &3)
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::IndexNotFound);
+
+        let notes = db.collection::<TestStruct>("notes").await.unwrap();
+        let found = notes
+            .find_by_index::<TestStruct, String>("foo", &key)
+            .await
+            .unwrap();
+        assert_eq!(found.len(), 1);
+
+        fs::remove_file(".rebuilt_indexes.db.ron").unwrap();
+        fs::remove_file(".rebuilt_indexes.db.indexes.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn index_built_from_loaded_data() {
+        {
+            let db = RonDb::new::<TestStruct>(".index_load.db").unwrap();
+            db.insert_one(TestStruct {
+                foo: "one".to_owned(),
+            })
+            .await
+            .unwrap();
+        }
+
+        let db = RonDb::new::<TestStruct>(".index_load.db").unwrap();
+        db.create_index("foo", |t: &TestStruct| t.foo.clone())
+            .await
+            .unwrap();
+        let result: Vec<Document<TestStruct>> =
+            db.find_by_index("foo", &"one".to_owned()).await.unwrap();
+        assert_eq!(result.len(), 1);
+        fs::remove_file(".index_load.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn unique_index() {
+        let db = RonDb::new::<TestStruct>(".unique_index.db").unwrap();
+        db.create_unique_index("foo", |t: &TestStruct| t.foo.clone())
+            .await
+            .unwrap();
+
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        let two = TestStruct {
+            foo: "two".to_owned(),
+        };
+        let doc = db.insert_one(one.clone()).await.unwrap();
+        let other = db.insert_one(two.clone()).await.unwrap();
+
+        let err = db.insert_one(one.clone()).await.unwrap_err();
+        assert_eq!(
+            err.kind(),
+            RedDbErrorKind::UniqueViolation {
+                index: "foo",
+                _id: doc._id
+            }
+        );
+
+        let err = db.update_one(&other._id, one.clone()).await.unwrap_err();
+        assert_eq!(
+            err.kind(),
+            RedDbErrorKind::UniqueViolation {
+                index: "foo",
+                _id: doc._id
+            }
+        );
+        assert!(db.update_one(&doc._id, one.clone()).await.unwrap());
+
+        let err = db.update(&two, &one).await.unwrap_err();
+        assert_eq!(
+            err.kind(),
+            RedDbErrorKind::UniqueViolation {
+                index: "foo",
+                _id: doc._id
+            }
+        );
+        let found: Document<TestStruct> = db.find_one(&other._id).await.unwrap();
+        assert_eq!(found.data, two);
+        fs::remove_file(".unique_index.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn unique_index_rejects_whole_batch() {
+        let db = RonDb::new::<TestStruct>(".unique_batch.db").unwrap();
+        db.create_unique_field::<TestStruct>("foo").await.unwrap();
+
+        let one = TestStruct {
+            foo: "one".to_owned(),
+        };
+        let two = TestStruct {
+            foo: "two".to_owned(),
+        };
+        let three = TestStruct {
+            foo: "three".to_owned(),
+        };
+        db.insert_one(one.clone()).await.unwrap();
+        let persisted = fs::read_to_string(".unique_batch.db.ron").unwrap();
+
+        let err = db.insert(vec![two.clone(), one.clone()]).await.unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            RedDbErrorKind::UniqueViolation { index: "foo", .. }
+        ));
+        let err = db
+            .insert(vec![three.clone(), three.clone()])
+            .await
+            .unwrap_err();
+        assert!(matches!(
+            err.kind(),
+            RedDbErrorKind::UniqueViolation { index: "foo", .. }
+        ));
+
+        let all: Vec<Document<TestStruct>> = db.find_all().await.unwrap();
+        assert_eq!(all.len(), 1);
+        assert_eq!(
+            fs::read_to_string(".unique_batch.db.ron").unwrap(),
+            persisted
+        );
+
+        db.insert(vec![two, three]).await.unwrap();
+        fs::remove_file(".unique_batch.db.ron").unwrap();
+        fs::remove_file(".unique_batch.db.indexes.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn find_range() {
+        let db = RonDb::new::<TestStruct>(".find_range.db").unwrap();
+        db.create_range_index("len", |t: &TestStruct| t.foo.len())
+            .await
+            .unwrap();
+
+        let values = vec!["a", "bbbb", "cc", "ddd", "eeeee"]
+            .into_iter()
+            .map(|foo| TestStruct {
+                foo: foo.to_owned(),
+            })
+            .collect();
+        let docs = db.insert(values).await.unwrap();
+
+        let result: Vec<Document<TestStruct>> = db.find_range("len", 2usize..5).await.unwrap();
+        let found: Vec<&str> = result.iter().map(|doc| doc.data.foo.as_str()).collect();
+        assert_eq!(found, vec!["cc", "ddd", "bbbb"]);
+
+        let result: Vec<Document<TestStruct>> = db.find_range_rev("len", ..=3usize).await.unwrap();
+        let found: Vec<&str> = result.iter().map(|doc| doc.data.foo.as_str()).collect();
+        assert_eq!(found, vec!["ddd", "cc", "a"]);
+
+        db.update_one(
+            &docs[0]._id,
+            TestStruct {
+                foo: "ffffff".to_owned(),
+            },
+        )
+        .await
+        .unwrap();
+        let result: Vec<Document<TestStruct>> = db.find_range("len", 5usize..).await.unwrap();
+        let found: Vec<&str> = result.iter().map(|doc| doc.data.foo.as_str()).collect();
+        assert_eq!(found, vec!["eeeee", "ffffff"]);
+        fs::remove_file(".find_range.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn search() {
+        let db = RonDb::new::<TestStruct>(".search.db").unwrap();
+        let values = vec![
+            "RedDb is a fast embedded db",
+            "An embedded database written in Rust",
+            "Fast cars and slow boats",
+        ]
+        .into_iter()
+        .map(|foo| TestStruct {
+            foo: foo.to_owned(),
+        })
+        .collect();
+        let docs = db.insert(values).await.unwrap();
+        db.create_text_index::<TestStruct>("text", &["foo"])
+            .await
+            .unwrap();
+
+        let result: Vec<(Document<TestStruct>, f64)> =
+            db.search("text", "fast embedded db").await.unwrap();
+        assert_eq!(result.len(), 3);
+        assert_eq!(result[0].0._id, docs[0]._id);
+
+        let result: Vec<(Document<TestStruct>, f64)> =
+            db.search("text", "datab* rst~").await.unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].0._id, docs[1]._id);
+
+        db.delete_one::<TestStruct>(&docs[0]._id).await.unwrap();
+        let result: Vec<(Document<TestStruct>, f64)> = db.search("text", "EMBEDDED").await.unwrap();
+        assert_eq!(result.len(), 1);
+        drop(db);
+
+        // the index is rebuilt when the database is loaded
+        let db = RonDb::new::<TestStruct>(".search.db").unwrap();
+        let result: Vec<(Document<TestStruct>, f64)> = db.search("text", "embedded").await.unwrap();
+        assert_eq!(result.len(), 1);
+        assert_eq!(result[0].0._id, docs[1]._id);
+        fs::remove_file(".search.db.ron").unwrap();
+        fs::remove_file(".search.db.indexes.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn find_all_with_pagination() {
+        let db = RonDb::new::<TestStruct>(".find_all_with.db").unwrap();
+        let values = vec!["d", "b", "e", "a", "c"]
+            .into_iter()
+            .map(|foo| TestStruct {
+                foo: foo.to_owned(),
+            })
+            .collect();
+        db.insert(values).await.unwrap();
+
+        let options = FindOptions {
+            sort_by: Some(Sort::asc("foo")),
+            limit: Some(2),
+            ..FindOptions::default()
+        };
+        let page: Page<TestStruct> = db.find_all_with(&options).await.unwrap();
+        let found: Vec<&str> = page.docs.iter().map(|doc| doc.data.foo.as_str()).collect();
+        assert_eq!(found, vec!["a", "b"]);
+
+        db.insert_one(TestStruct {
+            foo: "aa".to_owned(),
+        })
+        .await
+        .unwrap();
+
+        // reopening compacts the file, cursors keep working
+        let db = RonDb::new::<TestStruct>(".find_all_with.db").unwrap();
+        let options = FindOptions {
+            after_cursor: page.next_cursor,
+            ..options
         };
-        let doc: Document<TestStruct> = db.insert_document(data).await.unwrap();
-        let find: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
-        assert_eq!(find.data, doc.data);
+        let page: Page<TestStruct> = db.find_all_with(&options).await.unwrap();
+        let found: Vec<&str> = page.docs.iter().map(|doc| doc.data.foo.as_str()).collect();
+        assert_eq!(found, vec!["c", "d"]);
+
+        let options = FindOptions {
+            sort_by: Some(Sort::desc("foo")),
+            skip: 1,
+            ..FindOptions::default()
+        };
+        let page: Page<TestStruct> = db
+            .find_where_with(|t: &TestStruct| t.foo != "e", &options)
+            .await
+            .unwrap();
+        let found: Vec<&str> = page.docs.iter().map(|doc| doc.data.foo.as_str()).collect();
+        assert_eq!(found, vec!["c", "b", "aa", "a"]);
+        assert_eq!(page.next_cursor, None);
+        fs::remove_file(".find_all_with.db.ron").unwrap();
     }
     #[tokio::test]
-    async fn find_uuids() {
-        let db = RonDb::new::<TestStruct>(".test.db").unwrap();
-        let doc: Document<TestStruct> = db
-            .insert_document(TestStruct {
-                foo: "test".to_owned(),
+    async fn aggregations() {
+        let db = RonDb::new::<TestStruct>(".aggregations.db").unwrap();
+        let values = vec!["a", "bb", "a", "ccc", "bb", "a"]
+            .into_iter()
+            .map(|foo| TestStruct {
+                foo: foo.to_owned(),
             })
+            .collect();
+        db.insert(values).await.unwrap();
+
+        assert_eq!(db.count().await.unwrap(), 6);
+        assert!(db.exists(|t: &TestStruct| t.foo == "ccc").await.unwrap());
+        assert!(!db.exists(|t: &TestStruct| t.foo == "d").await.unwrap());
+
+        let distinct = db.distinct::<TestStruct>("foo").await.unwrap();
+        assert_eq!(
+            distinct,
+            vec![Value::from("a"), Value::from("bb"), Value::from("ccc")]
+        );
+
+        let scanned = db.facets::<TestStruct>("foo").await.unwrap();
+        // an index named after the field isn't known to key on it
+        db.create_index("foo", |t: &TestStruct| t.foo.len())
             .await
             .unwrap();
+        assert_eq!(db.facets::<TestStruct>("foo").await.unwrap(), scanned);
+        db.create_field_index::<TestStruct>("foo").await.unwrap();
+        let indexed = db.facets::<TestStruct>("foo").await.unwrap();
+        assert_eq!(scanned, indexed);
+        assert_eq!(indexed[0], (Value::from("a"), 3));
 
-        let doc2: Document<TestStruct> = db
-            .insert_document(TestStruct {
-                foo: "test2".to_owned(),
-            })
+        let groups = db
+            .group_by(
+                |t: &TestStruct| t.foo.clone(),
+                |t: &TestStruct| t.foo.len() as f64,
+            )
             .await
             .unwrap();
+        assert_eq!(groups.len(), 3);
+        assert_eq!(groups["bb"].count, 2);
+        assert_eq!(groups["bb"].sum, 4.0);
+        assert_eq!(groups["ccc"].avg(), 3.0);
 
-        let doc3: Document<TestStruct> = db
-            .insert_document(TestStruct {
-                foo: "test".to_owned(),
-            })
+        db.create_index("len", |t: &TestStruct| t.foo.len())
             .await
             .unwrap();
-        let uuids: Vec<Uuid> = db
-            .find_uuids(&TestStruct {
-                foo: "test".to_owned(),
+        let indexed = db
+            .group_by_index::<TestStruct, usize, _>("len", |t| t.foo.len() as f64)
+            .await
+            .unwrap();
+        assert_eq!(indexed.len(), 3);
+        assert_eq!(indexed[&2], groups["bb"]);
+        assert_eq!(indexed[&1].count, 3);
+        fs::remove_file(".aggregations.db.ron").unwrap();
+        fs::remove_file(".aggregations.db.indexes.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn find_all_stream() {
+        let db = RonDb::new::<TestStruct>(".find_all_stream.db").unwrap();
+        let values: Vec<TestStruct> = (0..STREAM_CHUNK_SIZE + 10)
+            .map(|i| TestStruct {
+                foo: (i % 2).to_string(),
+            })
+            .collect();
+        db.insert(values).await.unwrap();
+
+        let docs: Vec<Document<TestStruct>> = db.find_all_stream().try_collect().await.unwrap();
+        assert_eq!(docs.len(), STREAM_CHUNK_SIZE + 10);
+
+        let search = TestStruct {
+            foo: "1".to_owned(),
+        };
+        let docs: Vec<Document<TestStruct>> = db.find_stream(&search).try_collect().await.unwrap();
+        assert_eq!(docs.len(), (STREAM_CHUNK_SIZE + 10) / 2);
+
+        #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+        struct Other {
+            bar: u32,
+        }
+        let results: Vec<Result<Document<Other>>> = db.find_all_stream().collect().await;
+        assert_eq!(results.len(), STREAM_CHUNK_SIZE + 10);
+        assert!(results
+            .iter()
+            .all(|result| result.as_ref().unwrap_err().kind() == RedDbErrorKind::Deserialization));
+        fs::remove_file(".find_all_stream.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn collections() {
+        #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+        struct Order {
+            total: u32,
+        }
+
+        {
+            let db = RonDb::new::<TestStruct>(".collections.db").unwrap();
+            let orders = db.collection::<Order>("orders").await.unwrap();
+            let same = db.collection::<Order>("orders").await.unwrap();
+            assert!(Arc::ptr_eq(&orders, &same));
+            let err = db.collection::<TestStruct>("orders").await.unwrap_err();
+            assert_eq!(err.kind(), RedDbErrorKind::CollectionType);
+
+            db.insert_one(TestStruct {
+                foo: "one".to_owned(),
             })
             .await
             .unwrap();
+            let order = orders.insert_one(Order { total: 10 }).await.unwrap();
+            orders
+                .update_one(&order._id, Order { total: 20 })
+                .await
+                .unwrap();
+            orders.insert_one(Order { total: 30 }).await.unwrap();
+            assert_eq!(db.count().await.unwrap(), 1);
+            assert_eq!(orders.count().await.unwrap(), 2);
 
-        assert_eq!(uuids.contains(&doc._id), true);
-        assert_eq!(uuids.contains(&doc2._id), false);
-        assert_eq!(uuids.contains(&doc3._id), true);
+            let err = db.collection::<Order>("").await.unwrap_err();
+            assert_eq!(err.kind(), RedDbErrorKind::InvalidCollection);
+        }
 
-        fs::remove_file(".test.db.ron").unwrap();
+        // compacting the root collection keeps the lines of the others
+        let db = RonDb::new::<TestStruct>(".collections.db").unwrap();
+        let all: Vec<Document<TestStruct>> = db.find_all().await.unwrap();
+        assert_eq!(all.len(), 1);
+
+        let orders = db.collection::<Order>("orders").await.unwrap();
+        let mut totals: Vec<u32> = orders
+            .find_all::<Order>()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|doc| doc.data.total)
+            .collect();
+        totals.sort_unstable();
+        assert_eq!(totals, vec![20, 30]);
+
+        let persisted = fs::read_to_string(".collections.db.ron").unwrap();
+        assert_eq!(persisted.lines().count(), 3);
+        fs::remove_file(".collections.db.ron").unwrap();
     }
+
     #[tokio::test]
-    async fn insert_and_find_one() {
-        let db = RonDb::new::<TestStruct>(".insert_and_find_one.db").unwrap();
-        let doc: Document<TestStruct> = db
+    async fn hooks_and_validation() {
+        use std::sync::atomic::{AtomicUsize, Ordering};
+
+        #[derive(Debug, PartialEq)]
+        enum Invalid {
+            Empty,
+            Locked,
+        }
+
+        impl std::fmt::Display for Invalid {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                write!(f, "{:?}", self)
+            }
+        }
+
+        impl std::error::Error for Invalid {}
+
+        impl Validate for TestStruct {
+            type Error = Invalid;
+
+            fn validate(&self) -> std::result::Result<(), Invalid> {
+                if self.foo.is_empty() {
+                    return Err(Invalid::Empty);
+                }
+                Ok(())
+            }
+        }
+
+        let db = RonDb::new::<TestStruct>(".hooks.db").unwrap();
+        let inserted = Arc::new(AtomicUsize::new(0));
+        let counter = Arc::clone(&inserted);
+        db.validate::<TestStruct>().await;
+        db.before_delete(|_id: &Uuid, test: &TestStruct| match test.foo.as_str() {
+            "keep" => Err(Invalid::Locked),
+            _ => Ok(()),
+        })
+        .await;
+        db.after_insert(move |_doc: &Document<TestStruct>| {
+            counter.fetch_add(1, Ordering::SeqCst);
+        })
+        .await;
+
+        let empty = TestStruct { foo: String::new() };
+        let err = db.insert_one(empty.clone()).await.unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::Rejected);
+        assert_eq!(err.downcast_ref::<Invalid>(), Some(&Invalid::Empty));
+        assert_eq!(db.count().await.unwrap(), 0);
+
+        let keep = db
             .insert_one(TestStruct {
-                foo: "test".to_owned(),
+                foo: "keep".to_owned(),
             })
             .await
             .unwrap();
+        let err = db.update_one(&keep._id, empty).await.unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::Rejected);
+        let err = db.delete_one::<TestStruct>(&keep._id).await.unwrap_err();
+        assert_eq!(err.downcast_ref::<Invalid>(), Some(&Invalid::Locked));
+        assert_eq!(
+            db.find_one::<TestStruct>(&keep._id).await.unwrap().data.foo,
+            "keep"
+        );
 
-        let find: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
-        assert_eq!(find._id, doc._id);
-        assert_eq!(find.data, doc.data);
+        let other = db
+            .insert_one(TestStruct {
+                foo: "other".to_owned(),
+            })
+            .await
+            .unwrap();
+        db.delete_one::<TestStruct>(&other._id).await.unwrap();
+        assert_eq!(inserted.load(Ordering::SeqCst), 2);
 
-        fs::remove_file(".insert_and_find_one.db.ron").unwrap();
+        // rejected writes never reach the file
+        let persisted = fs::read_to_string(".hooks.db.ron").unwrap();
+        assert_eq!(persisted.lines().count(), 3);
+        fs::remove_file(".hooks.db.ron").unwrap();
     }
-    #[tokio::test]
-    async fn find() {
-        let db = RonDb::new::<TestStruct>(".find.db").unwrap();
 
+    #[tokio::test]
+    async fn watch() {
+        let db = RonDb::new::<TestStruct>(".watch.db").unwrap();
         let one = TestStruct {
-            foo: String::from("one"),
+            foo: "one".to_owned(),
         };
-
         let two = TestStruct {
-            foo: String::from("two"),
+            foo: "two".to_owned(),
         };
 
-        let many = vec![one.clone(), one.clone(), two.clone()];
-        db.insert(many).await.unwrap();
-        let result = db.find(&one).await.unwrap();
-        assert_eq!(result.len(), 2);
-        fs::remove_file(".find.db.ron").unwrap();
+        let mut all = Box::pin(db.watch::<TestStruct>());
+        let doc = db.insert_one(one.clone()).await.unwrap();
+        let mut by_id = Box::pin(db.watch_id::<TestStruct>(&doc._id));
+        let mut live = Box::pin(db.watch_where(|test: &TestStruct| test.foo == "two"));
+
+        db.insert_one(TestStruct {
+            foo: "other".to_owned(),
+        })
+        .await
+        .unwrap();
+        db.update_one(&doc._id, two.clone()).await.unwrap();
+        db.delete_one::<TestStruct>(&doc._id).await.unwrap();
+
+        let inserted = all.next().await.unwrap().unwrap();
+        assert_eq!(inserted._st, Status::In);
+        assert_eq!((inserted.before, inserted.after), (None, Some(one.clone())));
+        assert_eq!(all.next().await.unwrap().unwrap()._st, Status::In);
+
+        let expected = vec![
+            Change {
+                _id: doc._id,
+                _st: Status::Up,
+                before: Some(one),
+                after: Some(two.clone()),
+            },
+            Change {
+                _id: doc._id,
+                _st: Status::De,
+                before: Some(two),
+                after: None,
+            },
+        ];
+        for expected in expected {
+            assert_eq!(all.next().await.unwrap().unwrap(), expected);
+            assert_eq!(by_id.next().await.unwrap().unwrap(), expected);
+            assert_eq!(live.next().await.unwrap().unwrap(), expected);
+        }
+
+        fs::remove_file(".watch.db.ron").unwrap();
     }
+
     #[tokio::test]
-    async fn update_one() {
-        let db = RonDb::new::<TestStruct>(".update_one.db").unwrap();
-        let original = TestStruct {
-            foo: "hi".to_owned(),
+    async fn schema_migrations() {
+        #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+        struct UserV1 {
+            name: String,
+        }
+        #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+        struct UserV2 {
+            full_name: String,
+        }
+        #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+        struct User {
+            full_name: String,
+            admin: bool,
+        }
+
+        let rename = |mut value: Value| serde_json::json!({ "full_name": value["name"].take() });
+        let schema = || {
+            Schema::new(3)
+                .migrate(1, rename)
+                .migrate_typed(2, |user: UserV2| User {
+                    full_name: user.full_name,
+                    admin: false,
+                })
         };
 
-        let updated = TestStruct {
-            foo: "bye".to_owned(),
+        let id = {
+            let db = RonDb::new::<UserV1>(".schema.db").unwrap();
+            let doc = db
+                .insert_one(UserV1 {
+                    name: "ann".to_owned(),
+                })
+                .await
+                .unwrap();
+            doc._id
         };
 
-        let doc = db.insert_one(original.clone()).await.unwrap();
-        db.update_one(&doc._id, updated.clone()).await.unwrap();
-        let result: Document<TestStruct> = db.find_one(&doc._id).await.unwrap();
-        assert_eq!(result.data, updated);
-        fs::remove_file(".update_one.db.ron").unwrap();
+        // the step from version 2 is missing
+        let report =
+            RonDb::check_schema::<User>(".schema.db", "", &Schema::new(3).migrate(1, rename))
+                .await
+                .unwrap();
+        assert_eq!(report.migrated, 0);
+        assert_eq!(report.failed, vec![(id, 1)]);
+        let err = RonDb::with_schema::<User>(".schema.db", Schema::new(3).migrate(1, rename))
+            .unwrap_err();
+        assert_eq!(
+            err.kind(),
+            RedDbErrorKind::Migration {
+                _id: id,
+                version: 1
+            }
+        );
+
+        let report = RonDb::check_schema::<User>(".schema.db", "", &schema())
+            .await
+            .unwrap();
+        assert_eq!(report.migrated, 1);
+        assert!(report.failed.is_empty());
+
+        {
+            let db = RonDb::with_schema::<User>(".schema.db", schema()).unwrap();
+            let user: Document<User> = db.find_one(&id).await.unwrap();
+            assert_eq!(
+                user.data,
+                User {
+                    full_name: "ann".to_owned(),
+                    admin: false,
+                }
+            );
+            db.insert_one(User {
+                full_name: "bob".to_owned(),
+                admin: true,
+            })
+            .await
+            .unwrap();
+        }
+
+        let persisted = fs::read_to_string(".schema.db.ron").unwrap();
+        assert!(persisted.lines().all(|line| line.contains("_v:3")));
+        let report = RonDb::check_schema::<User>(".schema.db", "", &schema())
+            .await
+            .unwrap();
+        assert_eq!(report, MigrationReport::default());
+        fs::remove_file(".schema.db.ron").unwrap();
     }
 
     #[tokio::test]
-    async fn update() {
-        let db = RonDb::new::<TestStruct>(".update.db").unwrap();
-        let one = TestStruct {
-            foo: String::from("one"),
-        };
-        let two = TestStruct {
-            foo: String::from("two"),
-        };
+    async fn typed_db() {
+        #[derive(Clone, Debug, Serialize, PartialEq, Deserialize)]
+        struct Order {
+            total: u32,
+        }
 
-        let many = vec![one.clone(), one.clone(), two.clone()];
-        db.insert(many).await.unwrap();
-        let updated = db.update(&one, &two).await.unwrap();
-        assert_eq!(updated, 2);
-        let result = db.find(&two).await.unwrap();
-        assert_eq!(result.len(), 3);
-        fs::remove_file(".update.db.ron").unwrap();
+        let db = TypedRonDb::<TestStruct>::new(".typed_db.db").unwrap();
+        let doc = db
+            .insert_one(TestStruct {
+                foo: "one".to_owned(),
+            })
+            .await
+            .unwrap();
+        db.update_with(&doc._id, |test| test.foo.push('!'))
+            .await
+            .unwrap();
+        assert_eq!(db.find_one(&doc._id).await.unwrap().data.foo, "one!");
+
+        let orders = db.collection::<Order>("orders").await.unwrap();
+        orders.insert_one(Order { total: 10 }).await.unwrap();
+        let large = orders.find_where(|order| order.total > 5).await.unwrap();
+        assert_eq!(large.len(), 1);
+        assert_eq!(db.count().await.unwrap(), 1);
+
+        let untyped = RonDb::new::<TestStruct>(".typed_db_untyped.db").unwrap();
+        let typed = untyped.typed::<TestStruct>();
+        assert!(typed.find_all().await.unwrap().is_empty());
+
+        fs::remove_file(".typed_db.db.ron").unwrap();
+        fs::remove_file(".typed_db_untyped.db.ron").unwrap();
+    }
+    #[tokio::test]
+    async fn expiry() {
+        let start = SystemTime::now();
+        let clock = TestClock(Arc::new(std::sync::Mutex::new(start)));
+        let db = RonDb::new::<TestStruct>(".expiry.db")
+            .unwrap()
+            .with_clock(clock.clone());
+        let session = db
+            .insert_with_ttl(
+                TestStruct {
+                    foo: "session".to_owned(),
+                },
+                Duration::from_secs(10),
+            )
+            .await
+            .unwrap();
+        let cache = db
+            .insert_one(TestStruct {
+                foo: "cache".to_owned(),
+            })
+            .await
+            .unwrap();
+        assert!(session._exp.is_some());
+        assert!(db.find_one::<TestStruct>(&session._id).await.is_ok());
+
+        // expired documents are gone before the reaper runs
+        clock.advance(Duration::from_secs(11));
+        let err = db.find_one::<TestStruct>(&session._id).await.unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::NotFound { _id: session._id });
+        assert_eq!(db.find_all::<TestStruct>().await.unwrap().len(), 1);
+
+        db.set_expiry::<TestStruct>(&cache._id, clock.now() + Duration::from_secs(5))
+            .await
+            .unwrap();
+        clock.advance(Duration::from_secs(6));
+        assert_eq!(db.reap().await.unwrap(), 1);
+        assert_eq!(db.count().await.unwrap(), 0);
+
+        let kept = db
+            .insert_with_ttl(
+                TestStruct {
+                    foo: "kept".to_owned(),
+                },
+                Duration::from_secs(60),
+            )
+            .await
+            .unwrap();
+        drop(db);
+
+        // expiries and deletes of expired documents survive a restart
+        *clock.0.lock().unwrap() = start;
+        let db = Arc::new(
+            RonDb::with_schema_and_clock::<TestStruct, _>(
+                ".expiry.db",
+                Schema::default(),
+                clock.clone(),
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            db.find_all::<TestStruct>().await.unwrap(),
+            vec![kept.clone()]
+        );
+
+        db.spawn_reaper(Duration::from_millis(1));
+        clock.advance(Duration::from_secs(3600));
+        time::delay_for(Duration::from_millis(50)).await;
+        assert!(db.data.read().await.is_empty());
+
+        fs::remove_file(".expiry.db.ron").unwrap();
     }
 
     #[tokio::test]
-    async fn delete_and_find_one() {
-        let db = RonDb::new::<TestStruct>(".delete_one.db").unwrap();
-        let search = TestStruct {
-            foo: "test".to_owned(),
+    async fn timestamps() {
+        let start = SystemTime::now();
+        let clock = TestClock(Arc::new(std::sync::Mutex::new(start)));
+        let db = RonDb::new::<TestStruct>(".timestamps.db")
+            .unwrap()
+            .with_clock(clock.clone());
+        let doc = db
+            .insert_one(TestStruct {
+                foo: "one".to_owned(),
+            })
+            .await
+            .unwrap();
+        assert_eq!(
+            (doc._created, doc._updated),
+            (timestamp(start), timestamp(start))
+        );
+        assert_eq!(db.find_one::<TestStruct>(&doc._id).await.unwrap(), doc);
+
+        clock.advance(Duration::from_secs(5));
+        db.update_one(
+            &doc._id,
+            TestStruct {
+                foo: "two".to_owned(),
+            },
+        )
+        .await
+        .unwrap();
+        let updated = db.find_one::<TestStruct>(&doc._id).await.unwrap();
+        assert_eq!(updated._st, Status::Up);
+        assert_eq!(updated._created, doc._created);
+        assert_eq!(updated._updated, doc._created + 5000);
+        drop(db);
+
+        // compaction on load keeps them
+        let db = RonDb::new::<TestStruct>(".timestamps.db").unwrap();
+        assert_eq!(db.find_one::<TestStruct>(&doc._id).await.unwrap(), updated);
+
+        fs::remove_file(".timestamps.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn soft_delete() {
+        let open = |retention: Option<Duration>| {
+            RonDb::new::<TestStruct>(".soft_delete.db")
+                .unwrap()
+                .with_soft_delete(retention)
         };
+        let db = open(None);
+        let docs = db
+            .insert(vec![
+                TestStruct {
+                    foo: "one".to_owned(),
+                },
+                TestStruct {
+                    foo: "two".to_owned(),
+                },
+            ])
+            .await
+            .unwrap();
 
-        let doc = db.insert_one(search.clone()).await.unwrap();
-        let deleted = db.delete_one(&doc._id).await.unwrap();
+        let trashed = db.delete_one::<TestStruct>(&docs[0]._id).await.unwrap();
+        assert_eq!((&trashed._st, trashed._rev), (&Status::Tr, 2));
+        assert!(db.find_one::<TestStruct>(&docs[0]._id).await.is_err());
+        assert_eq!(db.count().await.unwrap(), 1);
+        let trash = db.list_trash::<TestStruct>().await.unwrap();
+        assert_eq!(trash, vec![trashed]);
+
+        let restored = db.restore::<TestStruct>(&docs[0]._id).await.unwrap();
+        let found = db.find_one::<TestStruct>(&docs[0]._id).await.unwrap();
+        assert_eq!(restored, found);
+        assert_eq!((restored.data, restored._rev), (docs[0].data.clone(), 3));
+        assert!(db.list_trash::<TestStruct>().await.unwrap().is_empty());
+        let err = db.restore::<TestStruct>(&docs[0]._id).await.unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::NotFound { _id: docs[0]._id });
+
+        db.delete_where(|_: &TestStruct| true).await.unwrap();
+        drop(db);
+
+        // the trash is kept when the database is compacted
+        let db = open(None);
+        assert_eq!(db.count().await.unwrap(), 0);
+        assert_eq!(db.list_trash::<TestStruct>().await.unwrap().len(), 2);
+        db.restore::<TestStruct>(&docs[1]._id).await.unwrap();
+        assert_eq!(db.empty_trash().await.unwrap(), 1);
+        drop(db);
+
+        let db = open(Some(Duration::from_secs(0)));
+        assert!(db.list_trash::<TestStruct>().await.unwrap().is_empty());
+        db.delete_one::<TestStruct>(&docs[1]._id).await.unwrap();
+        assert_eq!(db.list_trash::<TestStruct>().await.unwrap().len(), 1);
+        drop(db);
+
+        // and purged from it once its retention has elapsed
+        let db = open(None);
+        assert!(db.list_trash::<TestStruct>().await.unwrap().is_empty());
+        assert_eq!(db.count().await.unwrap(), 0);
+
+        fs::remove_file(".soft_delete.db.ron").unwrap();
+    }
+
+    #[tokio::test]
+    async fn trash_retention() {
+        let clock = TestClock(Arc::new(std::sync::Mutex::new(SystemTime::now())));
+        let open = || {
+            RonDb::with_schema_and_clock::<TestStruct, _>(
+                ".trash_retention.db",
+                Schema::default(),
+                clock.clone(),
+            )
+            .unwrap()
+            .with_soft_delete(Some(Duration::from_secs(3600)))
+        };
+        let db = open();
+        let doc = db
+            .insert_one(TestStruct {
+                foo: "one".to_owned(),
+            })
+            .await
+            .unwrap();
+        let trashed = db.delete_one::<TestStruct>(&doc._id).await.unwrap();
         assert_eq!(
-            deleted,
-            Document {
-                _id: doc._id,
-                data: doc.data,
-                _st: Status::De
-            }
+            trashed._purge,
+            Some(timestamp(clock.now() + Duration::from_secs(3600)))
         );
-        fs::remove_file(".delete_one.db.ron").unwrap();
+        drop(db);
+
+        // loading compares the purge time with the same clock
+        clock.advance(Duration::from_secs(1800));
+        let db = open();
+        assert_eq!(db.list_trash::<TestStruct>().await.unwrap().len(), 1);
+        drop(db);
+
+        clock.advance(Duration::from_secs(1800));
+        let db = open();
+        assert!(db.list_trash::<TestStruct>().await.unwrap().is_empty());
+
+        fs::remove_file(".trash_retention.db.ron").unwrap();
     }
 
-    async fn delete() {
-        let db = RonDb::new::<TestStruct>(".delete.db").unwrap();
-        let one = TestStruct {
-            foo: "one".to_owned(),
+    #[tokio::test]
+    async fn history() {
+        let start = SystemTime::now();
+        let clock = TestClock(Arc::new(std::sync::Mutex::new(start)));
+        let open = |history: History| {
+            let schema = Schema::default().with_history(history);
+            RonDb::with_schema_and_clock::<TestStruct, _>(".history.db", schema, clock.clone())
+                .unwrap()
         };
+        let foo = |doc: &Document<TestStruct>| doc.data.foo.to_owned();
 
-        let two = TestStruct {
-            foo: "two".to_owned(),
-        };
+        let db = open(History::Versions(3));
+        let doc = db
+            .insert_one(TestStruct {
+                foo: "v1".to_owned(),
+            })
+            .await
+            .unwrap();
+        for value in &["v2", "v3", "v4"] {
+            clock.advance(Duration::from_secs(1));
+            db.update_one(
+                &doc._id,
+                TestStruct {
+                    foo: value.to_string(),
+                },
+            )
+            .await
+            .unwrap();
+        }
 
-        let many = vec![one.clone(), one.clone(), two.clone()];
-        db.insert(many).await.unwrap();
-        let deleted = db.delete(&one).await.unwrap();
-        assert_eq!(deleted, 2);
+        let versions = db.history::<TestStruct>(&doc._id).await.unwrap();
+        let revs: Vec<u64> = versions.iter().map(|doc| doc._rev).collect();
+        assert_eq!(revs, vec![2, 3, 4]);
+        let at = db.find_one_at(&doc._id, At::Rev(3)).await.unwrap();
+        assert_eq!(foo(&at), "v3");
+        let at = db
+            .find_one_at(&doc._id, At::Time(start + Duration::from_millis(1500)))
+            .await
+            .unwrap();
+        assert_eq!(foo(&at), "v2");
+        let err = db
+            .find_one_at::<TestStruct>(&doc._id, At::Rev(1))
+            .await
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::NotFound { _id: doc._id });
 
-        let not_deleted = db.delete(&one).await.unwrap();
-        assert_eq!(not_deleted, 0);
-        fs::remove_file(".delete.db.ron").unwrap();
+        let reverted = db.revert::<TestStruct>(&doc._id, 2).await.unwrap();
+        assert_eq!((foo(&reverted), reverted._rev), ("v2".to_owned(), 5));
+        let versions = db.history::<TestStruct>(&doc._id).await.unwrap();
+        let revs: Vec<u64> = versions.iter().map(|doc| doc._rev).collect();
+        assert_eq!(revs, vec![3, 4, 5]);
+        drop(db);
+
+        // compaction keeps the versions of the history
+        let db = open(History::Versions(3));
+        let persisted = fs::read_to_string(".history.db.ron").unwrap();
+        assert_eq!(persisted.lines().count(), 3);
+        assert_eq!(db.history::<TestStruct>(&doc._id).await.unwrap().len(), 3);
+        drop(db);
+
+        let db = open(History::Since(Duration::from_secs(3600)));
+        assert_eq!(db.history::<TestStruct>(&doc._id).await.unwrap().len(), 3);
+        clock.advance(Duration::from_secs(7200));
+        let versions = db.history::<TestStruct>(&doc._id).await.unwrap();
+        assert_eq!(versions, vec![db.find_one(&doc._id).await.unwrap()]);
+        drop(db);
+
+        // compaction drops the same versions, with the same clock
+        let db = open(History::Since(Duration::from_secs(3600)));
+        let persisted = fs::read_to_string(".history.db.ron").unwrap();
+        assert_eq!(persisted.lines().count(), 1);
+        drop(db);
+
+        let db = open(History::default());
+        let persisted = fs::read_to_string(".history.db.ron").unwrap();
+        assert_eq!(persisted.lines().count(), 1);
+        assert_eq!(foo(&db.find_one(&doc._id).await.unwrap()), "v2");
+
+        fs::remove_file(".history.db.ron").unwrap();
     }
+
     #[tokio::test]
     async fn serialie_deserialize() {
         let db = RonDb::new::<TestStruct>(".serialize.db").unwrap();
@@ -0,0 +1,199 @@
+use serde_json::Value;
+use std::cmp::Ordering;
+use uuid::Uuid;
+
+use crate::document::Document;
+use crate::error::{RedDbErrorKind, Result};
+use crate::query;
+
+/// Sorting and pagination for `find_*_with` methods.
+///
+/// Results are always returned in a stable order: by the `sort_by` field
+/// first and by `_id` after that (or by `_id` only without `sort_by`).
+/// `after_cursor` takes the `next_cursor` of a previous `Page` and resumes
+/// right after its last document, so pages stay consistent when documents are
+/// inserted in between or the database is reopened.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct FindOptions {
+    pub sort_by: Option<Sort>,
+    pub limit: Option<usize>,
+    pub skip: usize,
+    pub after_cursor: Option<String>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sort {
+    pub field: String,
+    pub order: Order,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Order {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Page<T> {
+    pub docs: Vec<Document<T>>,
+    pub next_cursor: Option<String>,
+}
+
+impl Sort {
+    pub fn asc(field: &str) -> Self {
+        Sort {
+            field: field.to_owned(),
+            order: Order::Asc,
+        }
+    }
+
+    pub fn desc(field: &str) -> Self {
+        Sort {
+            field: field.to_owned(),
+            order: Order::Desc,
+        }
+    }
+}
+
+/// Position of a document in the result order.
+pub(crate) type SortKey = (Value, Uuid);
+
+impl FindOptions {
+    pub(crate) fn sort_key(&self, id: &Uuid, tree: Option<&Value>) -> SortKey {
+        let value = match (&self.sort_by, tree) {
+            (Some(sort), Some(tree)) => query::lookup(tree, &sort.field)
+                .cloned()
+                .unwrap_or(Value::Null),
+            _ => Value::Null,
+        };
+        (value, *id)
+    }
+
+    pub(crate) fn cmp(&self, a: &SortKey, b: &SortKey) -> Ordering {
+        let ordering = sort_cmp(&a.0, &b.0).then_with(|| a.1.cmp(&b.1));
+        match self.sort_by {
+            Some(Sort {
+                order: Order::Desc, ..
+            }) => ordering.reverse(),
+            _ => ordering,
+        }
+    }
+
+    /// Sorts `keys` and returns the ones in the requested page, together with
+    /// the cursor of the following page if there is one.
+    pub(crate) fn paginate(&self, mut keys: Vec<SortKey>) -> Result<(Vec<Uuid>, Option<String>)> {
+        keys.sort_by(|a, b| self.cmp(a, b));
+
+        let start = match &self.after_cursor {
+            Some(cursor) => {
+                let after = decode_cursor(cursor)?;
+                keys.partition_point(|key| self.cmp(key, &after) != Ordering::Greater)
+            }
+            None => 0,
+        };
+
+        let remaining = keys.len().saturating_sub(start + self.skip);
+        let take = self.limit.unwrap_or(remaining).min(remaining);
+        let end = start + self.skip + take;
+        let next_cursor = if end < keys.len() && take > 0 {
+            Some(encode_cursor(&keys[end - 1])?)
+        } else {
+            None
+        };
+
+        let ids = keys
+            .into_iter()
+            .skip(start + self.skip)
+            .take(take)
+            .map(|(_key, id)| id)
+            .collect();
+
+        Ok((ids, next_cursor))
+    }
+}
+
+/// Total order over value trees: null < bool < number < string < array < object.
+pub(crate) fn sort_cmp(a: &Value, b: &Value) -> Ordering {
+    fn rank(value: &Value) -> u8 {
+        match value {
+            Value::Null => 0,
+            Value::Bool(_) => 1,
+            Value::Number(_) => 2,
+            Value::String(_) => 3,
+            Value::Array(_) => 4,
+            Value::Object(_) => 5,
+        }
+    }
+
+    match (a, b) {
+        (Value::Array(a), Value::Array(b)) => a
+            .iter()
+            .zip(b.iter())
+            .map(|(a, b)| sort_cmp(a, b))
+            .find(|ordering| *ordering != Ordering::Equal)
+            .unwrap_or_else(|| a.len().cmp(&b.len())),
+        (Value::Object(_), Value::Object(_)) => a.to_string().cmp(&b.to_string()),
+        _ => query::compare(a, b).unwrap_or_else(|| rank(a).cmp(&rank(b))),
+    }
+}
+
+fn encode_cursor(key: &SortKey) -> Result<String> {
+    let json = serde_json::to_vec(key).map_err(|_| RedDbErrorKind::Serialization)?;
+    Ok(base64::encode_config(json, base64::URL_SAFE_NO_PAD))
+}
+
+fn decode_cursor(cursor: &str) -> Result<SortKey> {
+    let json = base64::decode_config(cursor, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| RedDbErrorKind::InvalidCursor)?;
+    Ok(serde_json::from_slice(&json).map_err(|_| RedDbErrorKind::InvalidCursor)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn paginate_with_cursor() {
+        let options = FindOptions {
+            sort_by: Some(Sort::desc("n")),
+            limit: Some(2),
+            ..FindOptions::default()
+        };
+        let keys: Vec<SortKey> = (0..5)
+            .map(|n| {
+                let id = Uuid::new_v4();
+                options.sort_key(&id, Some(&json!({ "n": n })))
+            })
+            .collect();
+        let expected: Vec<Uuid> = keys.iter().rev().map(|(_key, id)| *id).collect();
+
+        let (page, cursor) = options.paginate(keys.clone()).unwrap();
+        assert_eq!(page, expected[0..2]);
+
+        let options = FindOptions {
+            after_cursor: cursor,
+            ..options
+        };
+        let (page, cursor) = options.paginate(keys.clone()).unwrap();
+        assert_eq!(page, expected[2..4]);
+
+        let options = FindOptions {
+            after_cursor: cursor,
+            ..options
+        };
+        let (page, cursor) = options.paginate(keys).unwrap();
+        assert_eq!(page, expected[4..]);
+        assert_eq!(cursor, None);
+    }
+
+    #[test]
+    fn invalid_cursor() {
+        let options = FindOptions {
+            after_cursor: Some("not a cursor".to_owned()),
+            ..FindOptions::default()
+        };
+        let err = options.paginate(vec![]).unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::InvalidCursor);
+    }
+}
@@ -0,0 +1,347 @@
+use regex::Regex;
+use serde::de::{self, Deserializer};
+use serde::ser::Serializer;
+use serde::{Deserialize, Serialize};
+use serde_json::{Number, Value};
+use std::cmp::Ordering;
+use std::ops::Not;
+
+use crate::error::{RedDbErrorKind, Result};
+
+/// Filter evaluated against the serde value tree of a stored document.
+///
+/// Fields are addressed by dot separated paths (`address.city`, `tags.0`).
+/// Queries can be serialized, so the same filter can be kept in a config file
+/// or sent over the wire:
+///
+/// ```text
+/// {"and":[{"eq":{"field":"address.city","value":"Berlin"}},{"gte":{"field":"age","value":18}}]}
+/// ```
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Query {
+    Eq { field: String, value: Value },
+    Ne { field: String, value: Value },
+    Gt { field: String, value: Value },
+    Gte { field: String, value: Value },
+    Lt { field: String, value: Value },
+    Lte { field: String, value: Value },
+    In { field: String, values: Vec<Value> },
+    Contains { field: String, value: Value },
+    Exists { field: String },
+    Regex { field: String, pattern: Pattern },
+    And(Vec<Query>),
+    Or(Vec<Query>),
+    Not(Box<Query>),
+}
+
+/// Entry point of the query builder returned by [`Query::field`].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Field {
+    path: String,
+}
+
+/// Compiled regular expression that serializes as its source pattern.
+#[derive(Debug, Clone)]
+pub struct Pattern(Regex);
+
+impl Query {
+    pub fn field(path: &str) -> Field {
+        Field {
+            path: path.to_owned(),
+        }
+    }
+
+    pub fn and(self, other: Query) -> Query {
+        match self {
+            Query::And(mut queries) => {
+                queries.push(other);
+                Query::And(queries)
+            }
+            query => Query::And(vec![query, other]),
+        }
+    }
+
+    pub fn or(self, other: Query) -> Query {
+        match self {
+            Query::Or(mut queries) => {
+                queries.push(other);
+                Query::Or(queries)
+            }
+            query => Query::Or(vec![query, other]),
+        }
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self).map_err(|_| RedDbErrorKind::Serialization)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Query> {
+        Ok(serde_json::from_str(json).map_err(|_| RedDbErrorKind::InvalidQuery)?)
+    }
+
+    pub fn matches(&self, doc: &Value) -> bool {
+        match self {
+            Query::Eq { field, value } => lookup(doc, field).is_some_and(|v| equals(v, value)),
+            Query::Ne { field, value } => !lookup(doc, field).is_some_and(|v| equals(v, value)),
+            Query::Gt { field, value } => ordering(doc, field, value) == Some(Ordering::Greater),
+            Query::Gte { field, value } => matches!(
+                ordering(doc, field, value),
+                Some(Ordering::Greater) | Some(Ordering::Equal)
+            ),
+            Query::Lt { field, value } => ordering(doc, field, value) == Some(Ordering::Less),
+            Query::Lte { field, value } => matches!(
+                ordering(doc, field, value),
+                Some(Ordering::Less) | Some(Ordering::Equal)
+            ),
+            Query::In { field, values } => {
+                lookup(doc, field).is_some_and(|v| values.iter().any(|value| equals(v, value)))
+            }
+            Query::Contains { field, value } => match (lookup(doc, field), value) {
+                (Some(Value::Array(items)), value) => items.iter().any(|item| equals(item, value)),
+                (Some(Value::String(text)), Value::String(part)) => text.contains(part.as_str()),
+                _ => false,
+            },
+            Query::Exists { field } => lookup(doc, field).is_some(),
+            Query::Regex { field, pattern } => match lookup(doc, field) {
+                Some(Value::String(text)) => pattern.0.is_match(text),
+                _ => false,
+            },
+            Query::And(queries) => queries.iter().all(|query| query.matches(doc)),
+            Query::Or(queries) => queries.iter().any(|query| query.matches(doc)),
+            Query::Not(query) => !query.matches(doc),
+        }
+    }
+}
+
+impl Not for Query {
+    type Output = Query;
+
+    fn not(self) -> Query {
+        match self {
+            Query::Not(query) => *query,
+            query => Query::Not(Box::new(query)),
+        }
+    }
+}
+
+impl Field {
+    pub fn eq<V: Serialize>(self, value: V) -> Query {
+        Query::Eq {
+            field: self.path,
+            value: to_value(value),
+        }
+    }
+
+    pub fn ne<V: Serialize>(self, value: V) -> Query {
+        Query::Ne {
+            field: self.path,
+            value: to_value(value),
+        }
+    }
+
+    pub fn gt<V: Serialize>(self, value: V) -> Query {
+        Query::Gt {
+            field: self.path,
+            value: to_value(value),
+        }
+    }
+
+    pub fn gte<V: Serialize>(self, value: V) -> Query {
+        Query::Gte {
+            field: self.path,
+            value: to_value(value),
+        }
+    }
+
+    pub fn lt<V: Serialize>(self, value: V) -> Query {
+        Query::Lt {
+            field: self.path,
+            value: to_value(value),
+        }
+    }
+
+    pub fn lte<V: Serialize>(self, value: V) -> Query {
+        Query::Lte {
+            field: self.path,
+            value: to_value(value),
+        }
+    }
+
+    pub fn in_<V, I>(self, values: I) -> Query
+    where
+        V: Serialize,
+        I: IntoIterator<Item = V>,
+    {
+        Query::In {
+            field: self.path,
+            values: values.into_iter().map(to_value).collect(),
+        }
+    }
+
+    pub fn contains<V: Serialize>(self, value: V) -> Query {
+        Query::Contains {
+            field: self.path,
+            value: to_value(value),
+        }
+    }
+
+    pub fn exists(self) -> Query {
+        Query::Exists { field: self.path }
+    }
+
+    pub fn regex(self, pattern: &str) -> Result<Query> {
+        Ok(Query::Regex {
+            field: self.path,
+            pattern: Pattern::new(pattern)?,
+        })
+    }
+}
+
+impl Pattern {
+    pub fn new(pattern: &str) -> Result<Self> {
+        let regex = Regex::new(pattern).map_err(|_| RedDbErrorKind::InvalidQuery)?;
+        Ok(Pattern(regex))
+    }
+
+    pub fn as_str(&self) -> &str {
+        self.0.as_str()
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.as_str() == other.as_str()
+    }
+}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> std::result::Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> std::result::Result<Self, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map(Pattern).map_err(de::Error::custom)
+    }
+}
+
+/// Values that can not be represented as a serde value tree match as `null`.
+pub(crate) fn to_value<V: Serialize>(value: V) -> Value {
+    serde_json::to_value(value).unwrap_or(Value::Null)
+}
+
+pub(crate) fn lookup<'v>(doc: &'v Value, path: &str) -> Option<&'v Value> {
+    path.split('.').try_fold(doc, |value, key| match value {
+        Value::Object(map) => map.get(key),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    })
+}
+
+pub(crate) fn compare(a: &Value, b: &Value) -> Option<Ordering> {
+    match (a, b) {
+        // integers above 2^53 don't survive a trip through f64
+        (Value::Number(a), Value::Number(b)) => match (integer(a), integer(b)) {
+            (Some(a), Some(b)) => Some(a.cmp(&b)),
+            _ => a.as_f64()?.partial_cmp(&b.as_f64()?),
+        },
+        (Value::String(a), Value::String(b)) => Some(a.cmp(b)),
+        (Value::Bool(a), Value::Bool(b)) => Some(a.cmp(b)),
+        (Value::Null, Value::Null) => Some(Ordering::Equal),
+        _ => None,
+    }
+}
+
+fn integer(number: &Number) -> Option<i128> {
+    number
+        .as_i64()
+        .map(i128::from)
+        .or_else(|| number.as_u64().map(i128::from))
+}
+
+pub(crate) fn equals(a: &Value, b: &Value) -> bool {
+    match compare(a, b) {
+        Some(ordering) => ordering == Ordering::Equal,
+        None => a == b,
+    }
+}
+
+fn ordering(doc: &Value, field: &str, value: &Value) -> Option<Ordering> {
+    compare(lookup(doc, field)?, value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    fn doc() -> Value {
+        json!({
+            "name": "Ada",
+            "age": 36,
+            "address": { "city": "Berlin", "zip": "10115" },
+            "tags": ["admin", "dev"]
+        })
+    }
+
+    #[test]
+    fn field_comparisons() {
+        let doc = doc();
+        assert!(Query::field("address.city").eq("Berlin").matches(&doc));
+        assert!(Query::field("age").eq(36.0).matches(&doc));
+        assert!(Query::field("age").gte(18).matches(&doc));
+        assert!(!Query::field("age").lt(36).matches(&doc));
+        assert!(Query::field("name").ne("Bob").matches(&doc));
+        assert!(Query::field("tags.1").eq("dev").matches(&doc));
+        assert!(Query::field("address.city")
+            .in_(vec!["Paris", "Berlin"])
+            .matches(&doc));
+        assert!(Query::field("tags").contains("admin").matches(&doc));
+        assert!(Query::field("name").contains("d").matches(&doc));
+        assert!(Query::field("address.zip").exists().matches(&doc));
+        assert!(!Query::field("address.street").exists().matches(&doc));
+        assert!(Query::field("address.zip")
+            .regex("^10[0-9]+$")
+            .unwrap()
+            .matches(&doc));
+    }
+
+    #[test]
+    fn big_integers() {
+        let big = json!({ "n": 9_007_199_254_740_993u64 });
+        assert!(Query::field("n").gt(9_007_199_254_740_992u64).matches(&big));
+        assert!(Query::field("n").gt(-1).matches(&big));
+        assert!(!Query::field("n").eq(9_007_199_254_740_992u64).matches(&big));
+    }
+
+    #[test]
+    fn boolean_logic() {
+        let doc = doc();
+        let query = Query::field("address.city")
+            .eq("Berlin")
+            .and(Query::field("age").gte(18));
+        assert!(query.matches(&doc));
+        assert!(!(!query).matches(&doc));
+
+        let query = Query::field("age")
+            .lt(18)
+            .or(Query::field("tags").contains("dev"));
+        assert!(query.matches(&doc));
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let query = Query::field("address.city")
+            .eq("Berlin")
+            .and(!Query::field("name").regex("^B").unwrap());
+        let json = query.to_json().unwrap();
+        assert_eq!(Query::from_json(&json).unwrap(), query);
+
+        let parsed = Query::from_json(r#"{"gte":{"field":"age","value":18}}"#).unwrap();
+        assert_eq!(parsed, Query::field("age").gte(18));
+        assert!(Query::from_json(r#"{"regex":{"field":"name","pattern":"("}}"#).is_err());
+    }
+}
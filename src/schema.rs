@@ -0,0 +1,278 @@
+use serde::de::{self, Deserializer, MapAccess, SeqAccess, Visitor};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::collections::HashMap;
+use std::fmt::{self, Debug};
+use uuid::Uuid;
+
+use crate::document::Document;
+use crate::history::History;
+use crate::serializer::Serializer;
+
+/// Version of documents persisted before a schema was registered.
+pub(crate) const FIRST_VERSION: u32 = 1;
+
+/// A document on its way to the current version.
+enum Stage<'a> {
+    /// The persisted line, still at its original version.
+    Line(&'a [u8]),
+    /// Data written by a typed migration, in the storage format.
+    Data(Vec<u8>),
+    Value(Value),
+}
+
+type Migration<SE> = Box<dyn Fn(&SE, Stage<'_>) -> Option<Stage<'static>> + Send + Sync>;
+
+/// Current version of a document type and how to migrate older documents
+/// to it. Documents without a version are at version 1.
+///
+/// Each migration moves documents from version `from` to `from + 1`; they
+/// run in order when a collection is loaded, and the following compaction
+/// rewrites the documents at the current version. It also keeps the older
+/// versions of each document allowed by its `History`.
+pub struct Schema<SE> {
+    version: u32,
+    serializer: SE,
+    migrations: HashMap<u32, Migration<SE>>,
+    history: History,
+}
+
+/// Result of checking a collection against a schema without loading it.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct MigrationReport {
+    /// Documents that would be migrated to the current version.
+    pub migrated: usize,
+    /// Documents that can't be migrated, with the version they are at.
+    pub failed: Vec<(Uuid, u32)>,
+}
+
+impl<SE> Schema<SE>
+where
+    for<'de> SE: Serializer<'de>,
+{
+    pub fn new(version: u32) -> Self {
+        Self {
+            version,
+            serializer: SE::default(),
+            migrations: HashMap::new(),
+            history: History::default(),
+        }
+    }
+
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    /// Keeps the versions of each document allowed by `history` instead of
+    /// only the current one.
+    pub fn with_history(mut self, history: History) -> Self {
+        self.history = history;
+        self
+    }
+
+    pub fn history(&self) -> History {
+        self.history
+    }
+
+    /// Migrates documents of version `from` on their serde representation.
+    ///
+    /// Older documents are read without their type, so formats that don't
+    /// describe themselves fully lose information here: enum variants are
+    /// not kept by ron and bincode can't be read at all. Prefer `migrate_typed`
+    /// for those.
+    pub fn migrate<F>(self, from: u32, f: F) -> Self
+    where
+        F: Fn(Value) -> Value + Send + Sync + 'static,
+    {
+        self.with(
+            from,
+            Box::new(move |serializer: &SE, stage: Stage<'_>| {
+                let value = match stage {
+                    Stage::Line(line) => {
+                        let document: Document<AnyValue> = serializer.deserialize(line).ok()?;
+                        document.data.0
+                    }
+                    Stage::Data(data) => serializer.deserialize::<AnyValue>(&data).ok()?.0,
+                    Stage::Value(value) => value,
+                };
+                Some(Stage::Value(f(value)))
+            }),
+        )
+    }
+
+    /// Migrates documents of version `from`, stored as `A`, to `B`.
+    pub fn migrate_typed<A, B, F>(self, from: u32, f: F) -> Self
+    where
+        for<'de> A: Serialize + Deserialize<'de>,
+        for<'de> B: Serialize + Deserialize<'de>,
+        F: Fn(A) -> B + Send + Sync + 'static,
+    {
+        self.with(
+            from,
+            Box::new(move |serializer: &SE, stage: Stage<'_>| {
+                let old: A = match stage {
+                    Stage::Line(line) => serializer.deserialize::<Document<A>>(line).ok()?.data,
+                    Stage::Data(data) => serializer.deserialize(&data).ok()?,
+                    Stage::Value(value) => serde_json::from_value(value).ok()?,
+                };
+                let data = serializer.serialize(&f(old)).ok()?;
+                Some(Stage::Data(data))
+            }),
+        )
+    }
+
+    /// Data of a persisted document of version `from` at the current
+    /// version, or `None` if it can't be migrated.
+    pub(crate) fn upgrade<T>(&self, from: u32, line: &[u8]) -> Option<T>
+    where
+        for<'de> T: Serialize + Deserialize<'de>,
+    {
+        let mut stage = Stage::Line(line);
+        for version in from..self.version {
+            let migration = self.migrations.get(&version)?;
+            stage = migration(&self.serializer, stage)?;
+        }
+
+        match stage {
+            // documents newer than the schema
+            Stage::Line(_) => None,
+            Stage::Data(data) => self.serializer.deserialize(&data).ok(),
+            Stage::Value(value) => serde_json::from_value(value).ok(),
+        }
+    }
+
+    fn with(mut self, from: u32, migration: Migration<SE>) -> Self {
+        self.migrations.insert(from, migration);
+        self
+    }
+}
+
+impl<SE> Default for Schema<SE>
+where
+    for<'de> SE: Serializer<'de>,
+{
+    fn default() -> Self {
+        Self::new(FIRST_VERSION)
+    }
+}
+
+impl<SE> Debug for Schema<SE> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut migrations: Vec<&u32> = self.migrations.keys().collect();
+        migrations.sort();
+        f.debug_struct("Schema")
+            .field("version", &self.version)
+            .field("migrations", &migrations)
+            .field("history", &self.history)
+            .finish()
+    }
+}
+
+/// A `Value` read from any self-describing format.
+///
+/// Keys are read as strings rather than through `Value`'s own impl, which
+/// ron rejects for struct fields.
+struct AnyValue(Value);
+
+impl Serialize for AnyValue {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        self.0.serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for AnyValue {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        deserializer.deserialize_any(AnyValueVisitor)
+    }
+}
+
+struct AnyValueVisitor;
+
+impl<'de> Visitor<'de> for AnyValueVisitor {
+    type Value = AnyValue;
+
+    fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("any value")
+    }
+
+    fn visit_bool<E>(self, value: bool) -> Result<AnyValue, E> {
+        Ok(AnyValue(value.into()))
+    }
+
+    fn visit_i64<E>(self, value: i64) -> Result<AnyValue, E> {
+        Ok(AnyValue(value.into()))
+    }
+
+    fn visit_u64<E>(self, value: u64) -> Result<AnyValue, E> {
+        Ok(AnyValue(value.into()))
+    }
+
+    fn visit_f64<E>(self, value: f64) -> Result<AnyValue, E> {
+        Ok(AnyValue(value.into()))
+    }
+
+    fn visit_str<E>(self, value: &str) -> Result<AnyValue, E> {
+        Ok(AnyValue(value.into()))
+    }
+
+    fn visit_string<E>(self, value: String) -> Result<AnyValue, E> {
+        Ok(AnyValue(value.into()))
+    }
+
+    fn visit_unit<E>(self) -> Result<AnyValue, E> {
+        Ok(AnyValue(Value::Null))
+    }
+
+    fn visit_none<E>(self) -> Result<AnyValue, E> {
+        Ok(AnyValue(Value::Null))
+    }
+
+    fn visit_some<D: Deserializer<'de>>(self, deserializer: D) -> Result<AnyValue, D::Error> {
+        AnyValue::deserialize(deserializer)
+    }
+
+    fn visit_newtype_struct<D: Deserializer<'de>>(
+        self,
+        deserializer: D,
+    ) -> Result<AnyValue, D::Error> {
+        AnyValue::deserialize(deserializer)
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<AnyValue, A::Error> {
+        let mut values = Vec::new();
+        while let Some(AnyValue(value)) = seq.next_element()? {
+            values.push(value);
+        }
+        Ok(AnyValue(Value::Array(values)))
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<AnyValue, A::Error> {
+        let mut values = Map::new();
+        while let Some((Key(key), AnyValue(value))) = map.next_entry()? {
+            values.insert(key, value);
+        }
+        Ok(AnyValue(Value::Object(values)))
+    }
+}
+
+struct Key(String);
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct KeyVisitor;
+
+        impl<'de> Visitor<'de> for KeyVisitor {
+            type Value = Key;
+
+            fn expecting(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+                f.write_str("a string key")
+            }
+
+            fn visit_str<E: de::Error>(self, value: &str) -> Result<Key, E> {
+                Ok(Key(value.to_owned()))
+            }
+        }
+
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
@@ -5,6 +5,8 @@ pub enum Status {
     In,
     Up,
     De,
+    /// Deleted into the trash, with soft deletes.
+    Tr,
 }
 
 impl Default for Status {
@@ -1,23 +1,56 @@
 use async_trait::async_trait;
 use core::fmt::Debug;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 
 use super::Storage;
-use crate::document::Document;
+use crate::document::{first_version, Document, Record};
 use crate::error::{RedDbErrorKind, Result};
+use crate::expiry::{timestamp, NEVER};
+use crate::history;
+use crate::index::IndexDef;
+use crate::schema::{MigrationReport, Schema};
 use crate::serializer::{Serializer, Serializers};
 use crate::status::Status;
-use crate::RedDbHM;
+use crate::transaction::Frame;
+use crate::Versions;
+use std::io;
 use std::path::Path;
+use std::time::SystemTime;
 use tokio::fs::{File, OpenOptions};
 use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader, SeekFrom};
+use uuid::Uuid;
 
 use tokio::sync::Mutex;
 
+/// Fields of a persisted document that don't depend on its type.
+#[derive(Serialize, Deserialize)]
+struct Header {
+    _id: Uuid,
+    _st: Status,
+    #[serde(default)]
+    _rev: u64,
+    #[serde(default = "first_version")]
+    _v: u32,
+    #[serde(default)]
+    _col: String,
+    #[serde(default)]
+    _tx: Option<Frame>,
+    #[serde(default)]
+    _created: u64,
+    #[serde(default)]
+    _updated: u64,
+    #[serde(default)]
+    _exp: Option<u64>,
+    #[serde(default)]
+    _purge: Option<u64>,
+}
+
 #[derive(Debug)]
 pub struct FileStorage<SE> {
     file_path: String,
+    // index definitions are kept apart from the documents
+    index_path: String,
     serializer: SE,
     db_file: Mutex<File>,
 }
@@ -41,6 +74,7 @@ where
         Ok(Self {
             serializer: SE::default(),
             file_path: db_path.to_owned(),
+            index_path: [db_name, ".indexes", extension].concat(),
             db_file: Mutex::new(
                 OpenOptions::new()
                     .read(true)
@@ -48,55 +82,65 @@ where
                     .create(true)
                     .open(db_path)
                     .await
-                    .map_err(|_| RedDbErrorKind::StorageInit)
-                    .unwrap(),
+                    .map_err(|_| RedDbErrorKind::StorageInit)?,
             ),
         })
     }
 
-    async fn load<T>(&self) -> Result<RedDbHM>
+    async fn load<T, S>(
+        &self,
+        collection: &str,
+        schema: &Schema<S>,
+        now: SystemTime,
+    ) -> Result<Versions>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> S: Serializer<'de> + Send + Sync,
     {
-        let mut map: RedDbHM = HashMap::new();
+        // appends wait until the file is compacted
         let mut file = self.db_file.lock().await;
-        let reader = BufReader::new(&mut *file);
+        let lines = self.read_lines(&mut file).await?;
+        let (versions, others, report) = self.replay::<T, S>(collection, lines, schema, now)?;
 
-        let mut lines = reader.lines();
-
-        while let Some(line) = lines.next_line().await.unwrap() {
-            let byte_str = &line.into_bytes();
-            let document: Document<T> = self
-                .serializer
-                .deserialize(byte_str)
-                .map_err(|_| RedDbErrorKind::DataCorruption)
-                .unwrap();
-            let id = document._id;
-            let st = document._st;
-            let data = document.data;
-            let serialized = self.serializer.serialize(&data).unwrap();
-            if let Status::De = st {
-                map.remove(&id);
-            } else {
-                map.entry(id).or_insert_with(|| serialized);
-            }
+        if let Some(&(_id, version)) = report.failed.first() {
+            return Err(RedDbErrorKind::Migration { _id, version }.into());
         }
 
-        self.compact_data::<T>(&map)
+        self.compact_data::<T>(collection, schema.version(), &versions, &others)
             .await
             .map_err(|_| RedDbErrorKind::Compact)?;
 
-        Ok(map)
+        Ok(versions)
+    }
+
+    async fn check<T, S>(
+        &self,
+        collection: &str,
+        schema: &Schema<S>,
+        now: SystemTime,
+    ) -> Result<MigrationReport>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> S: Serializer<'de> + Send + Sync,
+    {
+        let mut file = self.db_file.lock().await;
+        let lines = self.read_lines(&mut file).await?;
+        let (_, _, report) = self.replay::<T, S>(collection, lines, schema, now)?;
+        Ok(report)
     }
 
     async fn persist<T>(&self, data: &[Document<T>]) -> Result<()>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + Sync,
     {
-        let serialized: Vec<u8> = data
-            .iter()
-            .flat_map(|doc| self.serializer.serialize::<Document<T>>(doc).unwrap())
-            .collect();
+        let mut serialized: Vec<u8> = Vec::new();
+        for doc in data {
+            let line = self
+                .serializer
+                .serialize::<Document<T>>(doc)
+                .map_err(|_| RedDbErrorKind::Serialization)?;
+            serialized.extend(line);
+        }
 
         self.append(&serialized)
             .await
@@ -104,35 +148,223 @@ where
 
         Ok(())
     }
+
+    async fn indexes(&self, collection: &str) -> Result<Vec<IndexDef>> {
+        let _file = self.db_file.lock().await;
+        let indexes = self.read_indexes().await?;
+        Ok(indexes
+            .into_iter()
+            .filter(|index| index.collection == collection)
+            .collect())
+    }
+
+    async fn save_index(&self, index: &IndexDef) -> Result<()> {
+        // definitions are rewritten one at a time
+        let _file = self.db_file.lock().await;
+        let mut indexes = self.read_indexes().await?;
+        indexes.retain(|other| other.collection != index.collection || other.name != index.name);
+        indexes.push(index.clone());
+        let data = self
+            .serializer
+            .serialize(&indexes)
+            .map_err(|_| RedDbErrorKind::Serialization)?;
+        self.flush_data(&self.index_path, &data).await
+    }
 }
 
 impl<SE> FileStorage<SE>
 where
     for<'de> SE: Serializer<'de> + Debug,
 {
-    pub async fn compact_data<T>(&self, data: &RedDbHM) -> Result<()>
+    /// Rewrites the file with one line per kept version of the documents of
+    /// `collection` at `version`, after the lines of the other collections.
+    pub async fn compact_data<T>(
+        &self,
+        collection: &str,
+        version: u32,
+        versions: &Versions,
+        others: &[u8],
+    ) -> Result<()>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq,
     {
-        let data: Vec<u8> = data
-            .iter()
-            .flat_map(|(id, data)| {
-                let data: T = self
+        let mut data: Vec<u8> = others.to_vec();
+        for (id, versions) in versions.iter() {
+            for record in versions {
+                let value: T = self
                     .serializer
-                    .deserialize(&*data)
-                    .map_err(|_| RedDbErrorKind::DataCorruption)
-                    .unwrap();
+                    .deserialize(&record.value)
+                    .map_err(|_| RedDbErrorKind::DataCorruption)?;
 
-                self.serializer
-                    .serialize(&Document::new(*id, data, Status::In))
-                    .map_err(|_| RedDbErrorKind::DataCorruption)
-                    .unwrap()
-            })
-            .collect();
+                let mut document = Document::new(*id, value, record.status());
+                document._rev = record.rev;
+                document._created = record.created;
+                document._updated = record.updated;
+                document._exp = record.expires;
+                document._purge = record.purge();
+                document._v = version;
+                document._col = collection.to_owned();
+                let line = self
+                    .serializer
+                    .serialize(&document)
+                    .map_err(|_| RedDbErrorKind::Serialization)?;
+                data.extend(line);
+            }
+        }
 
-        self.flush_data(&self.file_path, &data).await.unwrap();
+        self.flush_data(&self.file_path, &data).await
+    }
 
-        Ok(())
+    async fn read_indexes(&self) -> Result<Vec<IndexDef>> {
+        match tokio::fs::read(&self.index_path).await {
+            Ok(data) => Ok(self
+                .serializer
+                .deserialize(&data)
+                .map_err(|_| RedDbErrorKind::DataCorruption)?),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+            Err(_) => Err(RedDbErrorKind::ReadContent.into()),
+        }
+    }
+
+    /// Committed lines of the file with the collection they belong to. Lines
+    /// of a transaction are only returned once all of them have been read.
+    async fn read_lines(&self, file: &mut File) -> Result<Vec<(Header, String)>> {
+        file.seek(SeekFrom::Start(0))
+            .await
+            .map_err(|_| RedDbErrorKind::ReadContent)?;
+        let mut lines = BufReader::new(file).lines();
+
+        let mut committed: Vec<(Header, String)> = Vec::new();
+        // lines of transactions that are not complete yet
+        let mut pending: HashMap<Uuid, Vec<(Header, String)>> = HashMap::new();
+        let mut torn = false;
+
+        while let Some(line) = lines
+            .next_line()
+            .await
+            .map_err(|_| RedDbErrorKind::ReadContent)?
+        {
+            // only the last line can be cut short by an interrupted append
+            if torn {
+                return Err(RedDbErrorKind::DataCorruption.into());
+            }
+            let header: Header = match self.serializer.deserialize(line.as_bytes()) {
+                Ok(header) => header,
+                Err(_) => {
+                    torn = true;
+                    continue;
+                }
+            };
+
+            match header._tx {
+                Some(frame) => {
+                    let batch = pending.entry(frame.id).or_default();
+                    batch.push((header, line));
+                    if batch.len() == frame.len {
+                        committed.extend(pending.remove(&frame.id).unwrap_or_default());
+                    }
+                }
+                None => committed.push((header, line)),
+            }
+        }
+
+        Ok(committed)
+    }
+
+    /// Replays the lines of `collection`, migrating older documents with
+    /// `schema`, and returns the versions its history keeps with the
+    /// untouched lines of the other collections.
+    fn replay<T, S>(
+        &self,
+        collection: &str,
+        lines: Vec<(Header, String)>,
+        schema: &Schema<S>,
+        now: SystemTime,
+    ) -> Result<(Versions, Vec<u8>, MigrationReport)>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug,
+        for<'de> S: Serializer<'de>,
+    {
+        let mut versions: Versions = HashMap::new();
+        let mut others: Vec<u8> = Vec::new();
+        // only the last line of each document decides how it is reported
+        let mut migrated: HashSet<Uuid> = HashSet::new();
+        let mut failed: HashMap<Uuid, u32> = HashMap::new();
+        let now = timestamp(now);
+
+        for (header, line) in lines {
+            if header._col != collection {
+                // the type of other collections is unknown here, keep their lines
+                others.extend(line.into_bytes());
+                others.push(b'\n');
+                continue;
+            }
+
+            let id = header._id;
+            migrated.remove(&id);
+            failed.remove(&id);
+            if let Status::De = header._st {
+                versions.remove(&id);
+                continue;
+            }
+            // the trash is purged once its retention has elapsed
+            if header._purge.is_some_and(|at| at <= now) {
+                versions.remove(&id);
+                continue;
+            }
+
+            let data: Option<T> = if header._v == schema.version() {
+                let document: Document<T> = self
+                    .serializer
+                    .deserialize(line.as_bytes())
+                    .map_err(|_| RedDbErrorKind::DataCorruption)?;
+                Some(document.data)
+            } else {
+                schema.upgrade(header._v, line.as_bytes())
+            };
+
+            match data {
+                Some(data) => {
+                    let value = self
+                        .serializer
+                        .serialize(&data)
+                        .map_err(|_| RedDbErrorKind::DataCorruption)?;
+                    let record = Record {
+                        value,
+                        rev: header._rev,
+                        created: header._created,
+                        updated: header._updated,
+                        expires: header._exp,
+                        trashed: match header._st {
+                            Status::Tr => Some(header._purge.unwrap_or(NEVER)),
+                            _ => None,
+                        },
+                    };
+                    history::push(versions.entry(id).or_default(), record);
+                    if header._v != schema.version() {
+                        migrated.insert(id);
+                    }
+                }
+                None => {
+                    versions.remove(&id);
+                    failed.insert(id, header._v);
+                }
+            }
+        }
+
+        let history = schema.history();
+        for kept in versions.values_mut() {
+            history.retain(kept, |record| record.updated, now);
+        }
+
+        let mut failed: Vec<(Uuid, u32)> = failed.into_iter().collect();
+        failed.sort();
+        let report = MigrationReport {
+            migrated: migrated.len(),
+            failed,
+        };
+
+        Ok((versions, others, report))
     }
 
     /*
@@ -153,7 +385,7 @@ where
             .await
             .map_err(|_| RedDbErrorKind::FlushData)?;
         storage
-            .write_all(&data)
+            .write_all(data)
             .await
             .map_err(|_| RedDbErrorKind::FlushData)?;
         storage
@@ -165,9 +397,19 @@ where
 
     async fn append(&self, data: &[u8]) -> Result<()> {
         let mut storage = self.db_file.lock().await;
-        storage.seek(SeekFrom::End(0)).await.unwrap();
-        storage.write_all(&data).await.unwrap();
-        storage.sync_all().await.unwrap();
+        let len = storage
+            .seek(SeekFrom::End(0))
+            .await
+            .map_err(|_| RedDbErrorKind::AppendData)?;
+        let written = match storage.write_all(data).await {
+            Ok(()) => storage.sync_all().await,
+            Err(err) => Err(err),
+        };
+        if written.is_err() {
+            // drop the torn line, otherwise the next append is joined to it
+            let _ = storage.set_len(len).await;
+            return Err(RedDbErrorKind::AppendData.into());
+        }
         Ok(())
     }
 }
@@ -1,8 +1,12 @@
 use crate::error::Result;
-use crate::RedDbHM;
+use crate::index::IndexDef;
+use crate::schema::{MigrationReport, Schema};
+use crate::serializer::Serializer;
+use crate::Versions;
 use core::fmt::Debug;
 use serde::{Deserialize, Serialize};
 use std::marker::Sized;
+use std::time::SystemTime;
 
 mod file;
 use crate::document::Document;
@@ -14,10 +18,36 @@ pub trait Storage {
     async fn new(db_name: &str) -> Result<Self>
     where
         Self: Sized;
-    async fn load<T>(&self) -> Result<RedDbHM>
+    /// Loads the versions of the documents of `collection` kept by the
+    /// history of `schema`, migrating them. `now` decides which trashed
+    /// documents are purged and which versions the history keeps.
+    async fn load<T, S>(
+        &self,
+        collection: &str,
+        schema: &Schema<S>,
+        now: SystemTime,
+    ) -> Result<Versions>
     where
-        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync;
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> S: Serializer<'de> + Send + Sync;
+    /// Checks which documents of `collection` `load` would migrate, without
+    /// changing the storage.
+    async fn check<T, S>(
+        &self,
+        collection: &str,
+        schema: &Schema<S>,
+        now: SystemTime,
+    ) -> Result<MigrationReport>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync,
+        for<'de> S: Serializer<'de> + Send + Sync;
     async fn persist<T>(&self, records: &[Document<T>]) -> Result<()>
     where
         for<'de> T: Serialize + Deserialize<'de> + Debug + Send + Sync;
+    /// Definitions of the indexes of `collection` saved with `save_index`,
+    /// to rebuild them when the collection is loaded.
+    async fn indexes(&self, collection: &str) -> Result<Vec<IndexDef>>;
+    /// Saves `index`, replacing the definition of the same collection and
+    /// name.
+    async fn save_index(&self, index: &IndexDef) -> Result<()>;
 }
@@ -0,0 +1,256 @@
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::any::Any;
+use std::collections::{BTreeMap, HashMap};
+use uuid::Uuid;
+
+use crate::error::{RedDbErrorKind, Result};
+use crate::index::{Extractor, Indexer};
+use crate::query;
+use crate::serializer::Serializer;
+
+// BM25 parameters
+const K1: f64 = 1.2;
+const B: f64 = 0.75;
+
+/// Inverted index over the text extracted from each document.
+///
+/// Terms are lowercased alphanumeric runs. Searches are ranked with BM25 and
+/// every search term can be suffixed with `*` to match as a prefix or with `~`
+/// to match terms within a small edit distance.
+pub(crate) struct TextIndex<T> {
+    extractor: Extractor<T, String>,
+    postings: BTreeMap<String, HashMap<Uuid, u32>>,
+    terms: HashMap<Uuid, Vec<String>>,
+    lengths: HashMap<Uuid, u32>,
+    total_length: u64,
+}
+
+enum Term {
+    Exact(String),
+    Prefix(String),
+    Fuzzy(String),
+}
+
+impl<T> TextIndex<T> {
+    pub fn new<F>(extractor: F) -> Self
+    where
+        F: Fn(&T) -> Option<String> + Send + Sync + 'static,
+    {
+        Self {
+            extractor: Box::new(extractor),
+            postings: BTreeMap::new(),
+            terms: HashMap::new(),
+            lengths: HashMap::new(),
+            total_length: 0,
+        }
+    }
+
+    /// Index over the string fields at `fields`. Arrays of strings are
+    /// indexed element by element.
+    pub fn for_fields(fields: Vec<String>) -> Self
+    where
+        T: Serialize,
+    {
+        Self::new(move |value: &T| {
+            let tree = serde_json::to_value(value).ok()?;
+            let mut text = String::new();
+            for field in fields.iter() {
+                match query::lookup(&tree, field) {
+                    Some(Value::String(value)) => text.push_str(value),
+                    Some(Value::Array(values)) => {
+                        for value in values.iter().filter_map(|value| value.as_str()) {
+                            text.push_str(value);
+                            text.push(' ');
+                        }
+                    }
+                    _ => {}
+                }
+                text.push(' ');
+            }
+            Some(text)
+        })
+    }
+
+    /// Returns matching ids with their score, best match first.
+    pub fn search(&self, text: &str) -> Vec<(Uuid, f64)> {
+        let mut scores: HashMap<Uuid, f64> = HashMap::new();
+        for term in parse(text) {
+            // a term expanded to several index terms counts its best match only
+            let mut best: HashMap<Uuid, f64> = HashMap::new();
+            for (_term, docs) in self.expand(&term) {
+                for (id, score) in self.score(docs) {
+                    let entry = best.entry(id).or_insert(0.0);
+                    *entry = entry.max(score);
+                }
+            }
+            for (id, score) in best {
+                *scores.entry(id).or_insert(0.0) += score;
+            }
+        }
+
+        let mut ranked: Vec<(Uuid, f64)> = scores.into_iter().collect();
+        ranked.sort_by(|(a_id, a), (b_id, b)| b.total_cmp(a).then_with(|| a_id.cmp(b_id)));
+        ranked
+    }
+
+    fn expand<'a>(
+        &'a self,
+        term: &'a Term,
+    ) -> Box<dyn Iterator<Item = (&'a String, &'a HashMap<Uuid, u32>)> + 'a> {
+        match term {
+            Term::Exact(term) => Box::new(self.postings.get_key_value(term).into_iter()),
+            Term::Prefix(prefix) => Box::new(
+                self.postings
+                    .range(prefix.to_owned()..)
+                    .take_while(move |(term, _docs)| term.starts_with(prefix.as_str())),
+            ),
+            Term::Fuzzy(fuzzy) => {
+                let max_distance = if fuzzy.chars().count() > 5 { 2 } else { 1 };
+                Box::new(
+                    self.postings
+                        .iter()
+                        .filter(move |(term, _docs)| distance(term, fuzzy) <= max_distance),
+                )
+            }
+        }
+    }
+
+    fn score<'a>(&'a self, docs: &'a HashMap<Uuid, u32>) -> impl Iterator<Item = (Uuid, f64)> + 'a {
+        let count = self.lengths.len() as f64;
+        let average = self.total_length as f64 / count.max(1.0);
+        let frequency = docs.len() as f64;
+        let idf = (1.0 + (count - frequency + 0.5) / (frequency + 0.5)).ln();
+
+        docs.iter().map(move |(id, tf)| {
+            let tf = f64::from(*tf);
+            let length = f64::from(self.lengths.get(id).copied().unwrap_or_default());
+            let norm = K1 * (1.0 - B + B * length / average.max(1.0));
+            (*id, idf * tf * (K1 + 1.0) / (tf + norm))
+        })
+    }
+}
+
+impl<SE, T> Indexer<SE> for TextIndex<T>
+where
+    for<'de> SE: Serializer<'de>,
+    for<'de> T: Serialize + Deserialize<'de> + 'static,
+{
+    fn insert(&mut self, serializer: &SE, id: &Uuid, value: &[u8]) -> Result<()> {
+        let value: T = serializer
+            .deserialize(value)
+            .map_err(|_| RedDbErrorKind::Deserialization)?;
+        let text = match (self.extractor)(&value) {
+            Some(text) => text,
+            None => return Ok(()),
+        };
+
+        let tokens = tokenize(&text);
+        let mut terms: Vec<String> = Vec::new();
+        for token in tokens.iter() {
+            let tf = self
+                .postings
+                .entry(token.to_owned())
+                .or_default()
+                .entry(*id)
+                .or_insert(0);
+            if *tf == 0 {
+                terms.push(token.to_owned());
+            }
+            *tf += 1;
+        }
+        self.terms.insert(*id, terms);
+        self.lengths.insert(*id, tokens.len() as u32);
+        self.total_length += tokens.len() as u64;
+        Ok(())
+    }
+
+    fn remove(&mut self, id: &Uuid) {
+        if let Some(length) = self.lengths.remove(id) {
+            self.total_length -= u64::from(length);
+        }
+        for term in self.terms.remove(id).unwrap_or_default() {
+            if let Some(docs) = self.postings.get_mut(&term) {
+                docs.remove(id);
+                if docs.is_empty() {
+                    self.postings.remove(&term);
+                }
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.postings.clear();
+        self.terms.clear();
+        self.lengths.clear();
+        self.total_length = 0;
+    }
+
+    fn as_any(&self) -> &dyn Any {
+        self
+    }
+}
+
+fn tokenize(text: &str) -> Vec<String> {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|token| !token.is_empty())
+        .map(|token| token.to_lowercase())
+        .collect()
+}
+
+fn parse(text: &str) -> Vec<Term> {
+    text.split_whitespace()
+        .flat_map(|word| {
+            let (word, kind): (&str, fn(String) -> Term) =
+                if let Some(word) = word.strip_suffix('*') {
+                    (word, Term::Prefix)
+                } else if let Some(word) = word.strip_suffix('~') {
+                    (word, Term::Fuzzy)
+                } else {
+                    (word, Term::Exact)
+                };
+            tokenize(word).into_iter().map(kind)
+        })
+        .collect()
+}
+
+/// Levenshtein distance between two terms.
+fn distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, a) in a.chars().enumerate() {
+        let mut current = vec![i + 1; b.len() + 1];
+        for (j, b) in b.iter().enumerate() {
+            let cost = if a == *b { 0 } else { 1 };
+            current[j + 1] = (previous[j] + cost)
+                .min(previous[j + 1] + 1)
+                .min(current[j] + 1);
+        }
+        previous = current;
+    }
+    previous[b.len()]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tokenize_and_parse() {
+        assert_eq!(
+            tokenize("Fast, embedded DB!"),
+            vec!["fast", "embedded", "db"]
+        );
+        let terms = parse("emb* Databse~ fast");
+        assert!(matches!(&terms[0], Term::Prefix(term) if term == "emb"));
+        assert!(matches!(&terms[1], Term::Fuzzy(term) if term == "databse"));
+        assert!(matches!(&terms[2], Term::Exact(term) if term == "fast"));
+    }
+
+    #[test]
+    fn edit_distance() {
+        assert_eq!(distance("database", "databse"), 1);
+        assert_eq!(distance("kitten", "sitting"), 3);
+        assert_eq!(distance("", "db"), 2);
+    }
+}
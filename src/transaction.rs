@@ -0,0 +1,78 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::{self, Debug};
+use std::sync::{Arc, Mutex, MutexGuard};
+use uuid::Uuid;
+
+/// Marks a persisted document as part of a transaction of `len` documents.
+/// `FileStorage::load` only replays a transaction once all of its documents
+/// have been read, so a batch cut short by a crash is ignored.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Frame {
+    pub id: Uuid,
+    pub len: usize,
+}
+
+pub(crate) enum Op<T> {
+    Insert(Uuid, T),
+    Update(Uuid, T),
+    Delete(Uuid),
+}
+
+/// Operations staged by the closure given to `RedDb::transaction`.
+///
+/// Nothing is visible to other readers until the closure returns `Ok` and
+/// the whole batch is applied. Ids of updated and deleted documents are
+/// checked when the transaction commits.
+pub struct Transaction<T> {
+    ops: Arc<Mutex<Vec<Op<T>>>>,
+}
+
+impl<T> Transaction<T> {
+    pub(crate) fn new() -> Self {
+        Self {
+            ops: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Stages a new document and returns the id it will be inserted with.
+    pub fn insert_one(&self, value: T) -> Uuid {
+        let id = Uuid::new_v4();
+        self.ops().push(Op::Insert(id, value));
+        id
+    }
+
+    pub fn update_one(&self, id: &Uuid, value: T) {
+        self.ops().push(Op::Update(*id, value));
+    }
+
+    pub fn delete_one(&self, id: &Uuid) {
+        self.ops().push(Op::Delete(*id));
+    }
+
+    pub(crate) fn take(&self) -> Vec<Op<T>> {
+        self.ops().drain(..).collect()
+    }
+
+    fn ops(&self) -> MutexGuard<'_, Vec<Op<T>>> {
+        // staged operations stay consistent even if a closure panicked
+        self.ops
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl<T> Clone for Transaction<T> {
+    fn clone(&self) -> Self {
+        Self {
+            ops: Arc::clone(&self.ops),
+        }
+    }
+}
+
+impl<T> Debug for Transaction<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Transaction")
+            .field("ops", &self.ops().len())
+            .finish()
+    }
+}
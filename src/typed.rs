@@ -0,0 +1,475 @@
+use crate::error::Result;
+use crate::schema::Schema;
+use crate::serializer::Serializer;
+use crate::storage::Storage;
+use crate::{
+    At, Change, Clock, Document, FindOptions, Page, Query, RedDb, Stats, Transaction, Update, Uuid,
+    Validate, Value,
+};
+use futures::stream::Stream;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt::{self, Debug};
+use std::future::Future;
+use std::hash::Hash;
+use std::marker::PhantomData;
+use std::ops::RangeBounds;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use tokio::task::JoinHandle;
+
+/// A `RedDb` bound to a single document type.
+///
+/// Every method takes and returns `T`, so there is nothing to turbofish and
+/// reading or writing a collection with another type does not compile. The
+/// handle is cheap to clone; clones share the same database.
+pub struct TypedDb<T, SE, ST> {
+    db: Arc<RedDb<SE, ST>>,
+    _doc: PhantomData<fn(T) -> T>,
+}
+
+impl<T, SE, ST> Clone for TypedDb<T, SE, ST> {
+    fn clone(&self) -> Self {
+        Self {
+            db: Arc::clone(&self.db),
+            _doc: PhantomData,
+        }
+    }
+}
+
+impl<T, SE: Debug, ST: Debug> Debug for TypedDb<T, SE, ST> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TypedDb").field("db", &self.db).finish()
+    }
+}
+
+impl<T, SE, ST: 'static> TypedDb<T, SE, ST>
+where
+    for<'de> T: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
+    for<'de> SE: Serializer<'de> + Debug + Send + Sync + 'static,
+    for<'de> ST: Storage + Debug + Send + Sync,
+{
+    pub fn new(db_name: &'static str) -> Result<Self> {
+        Ok(Self::from_db(Arc::new(RedDb::new::<T>(db_name)?)))
+    }
+
+    pub fn with_schema(db_name: &'static str, schema: Schema<SE>) -> Result<Self> {
+        let db = RedDb::with_schema::<T>(db_name, schema)?;
+        Ok(Self::from_db(Arc::new(db)))
+    }
+
+    pub fn with_schema_and_clock<C: Clock + 'static>(
+        db_name: &'static str,
+        schema: Schema<SE>,
+        clock: C,
+    ) -> Result<Self> {
+        let db = RedDb::with_schema_and_clock::<T, C>(db_name, schema, clock)?;
+        Ok(Self::from_db(Arc::new(db)))
+    }
+
+    pub(crate) fn from_db(db: Arc<RedDb<SE, ST>>) -> Self {
+        Self {
+            db,
+            _doc: PhantomData,
+        }
+    }
+
+    /// Opens the collection `name` of the same database file with documents
+    /// of type `U`.
+    pub async fn collection<U>(&self, name: &str) -> Result<TypedDb<U, SE, ST>>
+    where
+        for<'de> U: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
+    {
+        self.db.typed_collection::<U>(name).await
+    }
+
+    pub async fn collection_with_schema<U>(
+        &self,
+        name: &str,
+        schema: &Schema<SE>,
+    ) -> Result<TypedDb<U, SE, ST>>
+    where
+        for<'de> U: Serialize + Deserialize<'de> + Debug + PartialEq + Send + Sync + 'static,
+    {
+        let collection = self.db.collection_with_schema::<U>(name, schema).await?;
+        Ok(TypedDb::from_db(collection))
+    }
+
+    pub async fn insert_one(&self, value: T) -> Result<Document<T>>
+    where
+        T: Clone,
+    {
+        self.db.insert_one(value).await
+    }
+
+    pub async fn insert_with_ttl(&self, value: T, ttl: Duration) -> Result<Document<T>> {
+        self.db.insert_with_ttl(value, ttl).await
+    }
+
+    pub async fn set_expiry(&self, id: &Uuid, at: SystemTime) -> Result<Document<T>> {
+        self.db.set_expiry(id, at).await
+    }
+
+    pub async fn reap(&self) -> Result<usize> {
+        self.db.reap().await
+    }
+
+    pub fn spawn_reaper(&self, interval: Duration) -> JoinHandle<()> {
+        self.db.spawn_reaper(interval)
+    }
+
+    pub async fn insert_with_id(&self, id: &Uuid, value: T) -> Result<Document<T>> {
+        self.db.insert_with_id(id, value).await
+    }
+
+    pub async fn upsert_one(&self, id: &Uuid, value: T) -> Result<Document<T>> {
+        self.db.upsert_one(id, value).await
+    }
+
+    pub async fn insert(&self, values: Vec<T>) -> Result<Vec<Document<T>>> {
+        self.db.insert(values).await
+    }
+
+    pub async fn find_one(&self, id: &Uuid) -> Result<Document<T>> {
+        self.db.find_one(id).await
+    }
+
+    pub async fn find_many(&self, ids: &[Uuid]) -> Result<Vec<Result<Document<T>>>> {
+        self.db.find_many(ids).await
+    }
+
+    pub async fn find_all(&self) -> Result<Vec<Document<T>>> {
+        self.db.find_all().await
+    }
+
+    pub async fn find(&self, search: &T) -> Result<Vec<Document<T>>> {
+        self.db.find(search).await
+    }
+
+    pub fn find_all_stream(&self) -> impl Stream<Item = Result<Document<T>>> + '_ {
+        self.db.find_all_stream()
+    }
+
+    pub fn find_stream(&self, search: &T) -> impl Stream<Item = Result<Document<T>>> + '_ {
+        self.db.find_stream(search)
+    }
+
+    pub async fn find_where<F>(&self, predicate: F) -> Result<Vec<Document<T>>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.db.find_where(predicate).await
+    }
+
+    pub async fn find_query(&self, query: &Query) -> Result<Vec<Document<T>>> {
+        self.db.find_query(query).await
+    }
+
+    pub async fn find_all_with(&self, options: &FindOptions) -> Result<Page<T>> {
+        self.db.find_all_with(options).await
+    }
+
+    pub async fn find_where_with<F>(&self, predicate: F, options: &FindOptions) -> Result<Page<T>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.db.find_where_with(predicate, options).await
+    }
+
+    pub async fn find_query_with(&self, query: &Query, options: &FindOptions) -> Result<Page<T>> {
+        self.db.find_query_with(query, options).await
+    }
+
+    pub async fn count(&self) -> Result<usize> {
+        self.db.count().await
+    }
+
+    pub async fn count_where<F>(&self, predicate: F) -> Result<usize>
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.db.count_where(predicate).await
+    }
+
+    pub async fn exists<F>(&self, predicate: F) -> Result<bool>
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.db.exists(predicate).await
+    }
+
+    pub async fn update_one(&self, id: &Uuid, new_value: T) -> Result<bool> {
+        self.db.update_one(id, new_value).await
+    }
+
+    pub async fn update_one_if(
+        &self,
+        id: &Uuid,
+        expected_rev: u64,
+        new_value: T,
+    ) -> Result<Document<T>> {
+        self.db.update_one_if(id, expected_rev, new_value).await
+    }
+
+    pub async fn update_with<F>(&self, id: &Uuid, f: F) -> Result<Document<T>>
+    where
+        F: FnOnce(&mut T),
+    {
+        self.db.update_with(id, f).await
+    }
+
+    pub async fn update_where_with<P, F>(&self, predicate: P, f: F) -> Result<Vec<Document<T>>>
+    where
+        P: Fn(&T) -> bool,
+        F: Fn(&mut T),
+    {
+        self.db.update_where_with(predicate, f).await
+    }
+
+    pub async fn modify_one(&self, id: &Uuid, update: &Update) -> Result<Document<T>> {
+        self.db.modify_one(id, update).await
+    }
+
+    pub async fn modify_where(&self, query: &Query, update: &Update) -> Result<Vec<Document<T>>> {
+        self.db.modify_where(query, update).await
+    }
+
+    pub async fn update(&self, search: &T, new_value: &T) -> Result<usize>
+    where
+        T: Clone,
+    {
+        self.db.update(search, new_value).await
+    }
+
+    pub async fn update_where<F>(&self, predicate: F, new_value: &T) -> Result<usize>
+    where
+        T: Clone,
+        F: Fn(&T) -> bool,
+    {
+        self.db.update_where(predicate, new_value).await
+    }
+
+    pub async fn update_many_by_id(
+        &self,
+        values: Vec<(Uuid, T)>,
+    ) -> Result<Vec<Result<Document<T>>>>
+    where
+        T: Clone,
+    {
+        self.db.update_many_by_id(values).await
+    }
+
+    pub async fn delete_one(&self, id: &Uuid) -> Result<Document<T>> {
+        self.db.delete_one(id).await
+    }
+
+    pub async fn delete_many(&self, ids: &[Uuid]) -> Result<Vec<Document<T>>> {
+        self.db.delete_many(ids).await
+    }
+
+    pub async fn delete_many_by_id(&self, ids: &[Uuid]) -> Result<Vec<Result<Document<T>>>>
+    where
+        T: Clone,
+    {
+        self.db.delete_many_by_id(ids).await
+    }
+
+    pub async fn history(&self, id: &Uuid) -> Result<Vec<Document<T>>> {
+        self.db.history(id).await
+    }
+
+    pub async fn find_one_at(&self, id: &Uuid, at: At) -> Result<Document<T>> {
+        self.db.find_one_at(id, at).await
+    }
+
+    pub async fn revert(&self, id: &Uuid, rev: u64) -> Result<Document<T>> {
+        self.db.revert(id, rev).await
+    }
+
+    pub async fn restore(&self, id: &Uuid) -> Result<Document<T>> {
+        self.db.restore(id).await
+    }
+
+    pub async fn list_trash(&self) -> Result<Vec<Document<T>>> {
+        self.db.list_trash().await
+    }
+
+    pub async fn empty_trash(&self) -> Result<usize> {
+        self.db.empty_trash().await
+    }
+
+    pub async fn delete(&self, search: &T) -> Result<usize> {
+        self.db.delete(search).await
+    }
+
+    pub async fn delete_where<F>(&self, predicate: F) -> Result<usize>
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.db.delete_where(predicate).await
+    }
+
+    pub async fn transaction<F, Fut, R>(&self, f: F) -> Result<R>
+    where
+        F: FnOnce(Transaction<T>) -> Fut,
+        Fut: Future<Output = Result<R>>,
+    {
+        self.db.transaction(f).await
+    }
+
+    pub async fn before_insert<E, F>(&self, hook: F)
+    where
+        E: Error + Send + Sync + 'static,
+        F: Fn(&Uuid, &T) -> std::result::Result<(), E> + Send + Sync + 'static,
+    {
+        self.db.before_insert(hook).await
+    }
+
+    pub async fn before_update<E, F>(&self, hook: F)
+    where
+        E: Error + Send + Sync + 'static,
+        F: Fn(&Uuid, &T) -> std::result::Result<(), E> + Send + Sync + 'static,
+    {
+        self.db.before_update(hook).await
+    }
+
+    pub async fn before_delete<E, F>(&self, hook: F)
+    where
+        E: Error + Send + Sync + 'static,
+        F: Fn(&Uuid, &T) -> std::result::Result<(), E> + Send + Sync + 'static,
+    {
+        self.db.before_delete(hook).await
+    }
+
+    pub async fn after_insert<F>(&self, hook: F)
+    where
+        F: Fn(&Document<T>) + Send + Sync + 'static,
+    {
+        self.db.after_insert(hook).await
+    }
+
+    pub async fn after_update<F>(&self, hook: F)
+    where
+        F: Fn(&Document<T>) + Send + Sync + 'static,
+    {
+        self.db.after_update(hook).await
+    }
+
+    pub async fn after_delete<F>(&self, hook: F)
+    where
+        F: Fn(&Document<T>) + Send + Sync + 'static,
+    {
+        self.db.after_delete(hook).await
+    }
+
+    pub async fn validate(&self)
+    where
+        T: Validate,
+    {
+        self.db.validate::<T>().await
+    }
+
+    pub fn watch(&self) -> impl Stream<Item = Result<Change<T>>> {
+        self.db.watch()
+    }
+
+    pub fn watch_id(&self, id: &Uuid) -> impl Stream<Item = Result<Change<T>>> {
+        self.db.watch_id(id)
+    }
+
+    pub fn watch_where<F>(&self, predicate: F) -> impl Stream<Item = Result<Change<T>>>
+    where
+        F: Fn(&T) -> bool,
+    {
+        self.db.watch_where(predicate)
+    }
+
+    pub async fn distinct(&self, field: &str) -> Result<Vec<Value>> {
+        self.db.distinct::<T>(field).await
+    }
+
+    pub async fn facets(&self, field: &str) -> Result<Vec<(Value, usize)>> {
+        self.db.facets::<T>(field).await
+    }
+
+    pub async fn group_by<K, FK, FV>(&self, key_fn: FK, value_fn: FV) -> Result<HashMap<K, Stats>>
+    where
+        K: Hash + Eq,
+        FK: Fn(&T) -> K,
+        FV: Fn(&T) -> f64,
+    {
+        self.db.group_by(key_fn, value_fn).await
+    }
+
+    pub async fn group_by_index<K, FV>(&self, name: &str, value_fn: FV) -> Result<HashMap<K, Stats>>
+    where
+        K: Hash + Eq + Clone + 'static,
+        FV: Fn(&T) -> f64,
+    {
+        self.db.group_by_index(name, value_fn).await
+    }
+
+    pub async fn create_index<K, F>(&self, name: &'static str, extractor: F) -> Result<()>
+    where
+        K: Serialize + Hash + Eq + Clone + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        self.db.create_index(name, extractor).await
+    }
+
+    pub async fn create_unique_index<K, F>(&self, name: &'static str, extractor: F) -> Result<()>
+    where
+        K: Serialize + Hash + Eq + Clone + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        self.db.create_unique_index(name, extractor).await
+    }
+
+    pub async fn create_field_index(&self, path: &'static str) -> Result<()> {
+        self.db.create_field_index::<T>(path).await
+    }
+
+    pub async fn create_unique_field(&self, path: &'static str) -> Result<()> {
+        self.db.create_unique_field::<T>(path).await
+    }
+
+    pub async fn create_range_index<K, F>(&self, name: &'static str, extractor: F) -> Result<()>
+    where
+        K: Serialize + Ord + Clone + Send + Sync + 'static,
+        F: Fn(&T) -> K + Send + Sync + 'static,
+    {
+        self.db.create_range_index(name, extractor).await
+    }
+
+    pub async fn create_text_index(&self, name: &'static str, fields: &[&str]) -> Result<()> {
+        self.db.create_text_index::<T>(name, fields).await
+    }
+
+    pub async fn find_by_index<K>(&self, name: &str, key: &K) -> Result<Vec<Document<T>>>
+    where
+        K: Hash + Eq + Clone + Send + Sync + 'static,
+    {
+        self.db.find_by_index(name, key).await
+    }
+
+    pub async fn find_range<K, R>(&self, name: &str, range: R) -> Result<Vec<Document<T>>>
+    where
+        K: Ord + Clone + Send + Sync + 'static,
+        R: RangeBounds<K>,
+    {
+        self.db.find_range(name, range).await
+    }
+
+    pub async fn find_range_rev<K, R>(&self, name: &str, range: R) -> Result<Vec<Document<T>>>
+    where
+        K: Ord + Clone + Send + Sync + 'static,
+        R: RangeBounds<K>,
+    {
+        self.db.find_range_rev(name, range).await
+    }
+
+    pub async fn search(&self, name: &str, text: &str) -> Result<Vec<(Document<T>, f64)>> {
+        self.db.search(name, text).await
+    }
+}
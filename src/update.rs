@@ -0,0 +1,440 @@
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Number, Value};
+use std::convert::TryFrom;
+
+use crate::error::{RedDbError, RedDbErrorKind, Result};
+use crate::query::{equals, to_value};
+
+/// Update operators applied to the serde value tree of a stored document.
+///
+/// Fields are addressed by dot separated paths like in `Query`. Updates can be
+/// built from JSON in the MongoDB format:
+///
+/// ```text
+/// {"$inc":{"visits":1},"$set":{"address.city":"Berlin"},"$unset":{"draft":""}}
+/// ```
+///
+/// They serialize as a list of such documents holding one operator each, which
+/// keeps the operators in order even when several of them touch the same path:
+///
+/// ```text
+/// [{"$push":{"tags":"a"}},{"$push":{"tags":"a"}},{"$inc":{"visits":1}}]
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "Value", into = "Value")]
+pub struct Update {
+    ops: Vec<Operator>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Operator {
+    Set(String, Value),
+    Unset(String),
+    Inc(String, Value),
+    Push(String, Value),
+    Pull(String, Value),
+    AddToSet(String, Value),
+    Rename(String, String),
+}
+
+impl Update {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set<V: Serialize>(self, path: &str, value: V) -> Self {
+        self.with(Operator::Set(path.to_owned(), to_value(value)))
+    }
+
+    pub fn unset(self, path: &str) -> Self {
+        self.with(Operator::Unset(path.to_owned()))
+    }
+
+    /// Adds `by` to the number at `path`, which is set to `by` if missing.
+    pub fn inc<V: Serialize>(self, path: &str, by: V) -> Self {
+        self.with(Operator::Inc(path.to_owned(), to_value(by)))
+    }
+
+    pub fn push<V: Serialize>(self, path: &str, value: V) -> Self {
+        self.with(Operator::Push(path.to_owned(), to_value(value)))
+    }
+
+    /// Removes every element equal to `value` from the array at `path`.
+    pub fn pull<V: Serialize>(self, path: &str, value: V) -> Self {
+        self.with(Operator::Pull(path.to_owned(), to_value(value)))
+    }
+
+    /// Pushes `value` unless the array at `path` already contains it.
+    pub fn add_to_set<V: Serialize>(self, path: &str, value: V) -> Self {
+        self.with(Operator::AddToSet(path.to_owned(), to_value(value)))
+    }
+
+    pub fn rename(self, from: &str, to: &str) -> Self {
+        self.with(Operator::Rename(from.to_owned(), to.to_owned()))
+    }
+
+    pub fn to_json(&self) -> Result<String> {
+        Ok(serde_json::to_string(self).map_err(|_| RedDbErrorKind::Serialization)?)
+    }
+
+    pub fn from_json(json: &str) -> Result<Update> {
+        Ok(serde_json::from_str(json).map_err(|_| RedDbErrorKind::InvalidUpdate)?)
+    }
+
+    /// Applies every operator in order and tells whether `doc` changed.
+    pub(crate) fn apply(&self, doc: &mut Value) -> Result<bool> {
+        let mut changed = false;
+        for op in self.ops.iter() {
+            changed |= op.apply(doc)?;
+        }
+        Ok(changed)
+    }
+
+    fn with(mut self, op: Operator) -> Self {
+        self.ops.push(op);
+        self
+    }
+}
+
+impl Operator {
+    fn name(&self) -> &'static str {
+        match self {
+            Operator::Set(..) => "$set",
+            Operator::Unset(..) => "$unset",
+            Operator::Inc(..) => "$inc",
+            Operator::Push(..) => "$push",
+            Operator::Pull(..) => "$pull",
+            Operator::AddToSet(..) => "$addToSet",
+            Operator::Rename(..) => "$rename",
+        }
+    }
+
+    fn parse(name: &str, path: String, value: Value) -> Result<Operator> {
+        let op = match name {
+            "$set" => Operator::Set(path, value),
+            "$unset" => Operator::Unset(path),
+            "$inc" => Operator::Inc(path, value),
+            "$push" => Operator::Push(path, value),
+            "$pull" => Operator::Pull(path, value),
+            "$addToSet" => Operator::AddToSet(path, value),
+            "$rename" => match value {
+                Value::String(to) => Operator::Rename(path, to),
+                _ => return Err(RedDbErrorKind::InvalidUpdate.into()),
+            },
+            _ => return Err(RedDbErrorKind::InvalidUpdate.into()),
+        };
+        Ok(op)
+    }
+
+    fn apply(&self, doc: &mut Value) -> Result<bool> {
+        match self {
+            Operator::Set(path, value) => {
+                let (parent, key) =
+                    parent(doc, path, true)?.ok_or(RedDbErrorKind::InvalidUpdate)?;
+                if get(parent, key) == Some(value) {
+                    return Ok(false);
+                }
+                set(parent, key, value.to_owned())?;
+                Ok(true)
+            }
+            Operator::Unset(path) => match parent(doc, path, false)? {
+                Some((parent, key)) => Ok(remove(parent, key).is_some()),
+                None => Ok(false),
+            },
+            Operator::Inc(path, by) => {
+                let (parent, key) =
+                    parent(doc, path, true)?.ok_or(RedDbErrorKind::InvalidUpdate)?;
+                let sum = match get(parent, key) {
+                    Some(current) => add(current, by)?,
+                    None => add(&Value::from(0), by)?,
+                };
+                if get(parent, key) == Some(&sum) {
+                    return Ok(false);
+                }
+                set(parent, key, sum)?;
+                Ok(true)
+            }
+            Operator::Push(path, value) | Operator::AddToSet(path, value) => {
+                let (parent, key) =
+                    parent(doc, path, true)?.ok_or(RedDbErrorKind::InvalidUpdate)?;
+                match get_mut(parent, key) {
+                    Some(Value::Array(items)) => {
+                        if let Operator::AddToSet(..) = self {
+                            if items.iter().any(|item| equals(item, value)) {
+                                return Ok(false);
+                            }
+                        }
+                        items.push(value.to_owned());
+                    }
+                    Some(_) => return Err(RedDbErrorKind::InvalidUpdate.into()),
+                    None => set(parent, key, Value::Array(vec![value.to_owned()]))?,
+                }
+                Ok(true)
+            }
+            Operator::Pull(path, value) => match parent(doc, path, false)? {
+                Some((parent, key)) => match get_mut(parent, key) {
+                    Some(Value::Array(items)) => {
+                        let len = items.len();
+                        items.retain(|item| !equals(item, value));
+                        Ok(items.len() != len)
+                    }
+                    Some(_) => Err(RedDbErrorKind::InvalidUpdate.into()),
+                    None => Ok(false),
+                },
+                None => Ok(false),
+            },
+            Operator::Rename(from, to) => {
+                let value = match parent(doc, from, false)? {
+                    Some((parent, key)) => remove(parent, key),
+                    None => None,
+                };
+                match value {
+                    Some(value) => {
+                        let (parent, key) =
+                            parent(doc, to, true)?.ok_or(RedDbErrorKind::InvalidUpdate)?;
+                        set(parent, key, value)?;
+                        Ok(true)
+                    }
+                    None => Ok(false),
+                }
+            }
+        }
+    }
+}
+
+impl From<Update> for Value {
+    fn from(update: Update) -> Value {
+        let ops = update.ops.into_iter().map(|op| {
+            let name = op.name();
+            let (path, value) = match op {
+                Operator::Set(path, value)
+                | Operator::Inc(path, value)
+                | Operator::Push(path, value)
+                | Operator::Pull(path, value)
+                | Operator::AddToSet(path, value) => (path, value),
+                Operator::Unset(path) => (path, Value::from("")),
+                Operator::Rename(from, to) => (from, Value::from(to)),
+            };
+            let mut paths = Map::new();
+            paths.insert(path, value);
+            let mut doc = Map::new();
+            doc.insert(name.to_owned(), Value::Object(paths));
+            Value::Object(doc)
+        });
+        Value::Array(ops.collect())
+    }
+}
+
+impl TryFrom<Value> for Update {
+    type Error = RedDbError;
+
+    fn try_from(value: Value) -> Result<Update> {
+        let docs = match value {
+            Value::Array(docs) => docs,
+            doc => vec![doc],
+        };
+
+        let mut update = Update::new();
+        for doc in docs {
+            let doc = match doc {
+                Value::Object(doc) => doc,
+                _ => return Err(RedDbErrorKind::InvalidUpdate.into()),
+            };
+            for (name, paths) in doc {
+                let paths = match paths {
+                    Value::Object(paths) => paths,
+                    _ => return Err(RedDbErrorKind::InvalidUpdate.into()),
+                };
+                for (path, value) in paths {
+                    update = update.with(Operator::parse(&name, path, value)?);
+                }
+            }
+        }
+        Ok(update)
+    }
+}
+
+/// Container holding the last segment of `path`, together with that segment.
+/// Missing objects along the way are created when `create` is set.
+fn parent<'v, 'p>(
+    doc: &'v mut Value,
+    path: &'p str,
+    create: bool,
+) -> Result<Option<(&'v mut Value, &'p str)>> {
+    let mut keys: Vec<&str> = path.split('.').collect();
+    let last = keys.pop().unwrap_or_default();
+
+    let mut current = doc;
+    for key in keys {
+        let next = match current {
+            Value::Object(map) => {
+                if create && !map.contains_key(key) {
+                    map.insert(key.to_owned(), Value::Object(Map::new()));
+                }
+                map.get_mut(key)
+            }
+            Value::Array(items) => match key.parse::<usize>() {
+                Ok(i) => items.get_mut(i),
+                Err(_) => None,
+            },
+            _ => None,
+        };
+        current = match next {
+            Some(value) => value,
+            None if create => return Err(RedDbErrorKind::InvalidUpdate.into()),
+            None => return Ok(None),
+        };
+    }
+    Ok(Some((current, last)))
+}
+
+fn get<'v>(parent: &'v Value, key: &str) -> Option<&'v Value> {
+    match parent {
+        Value::Object(map) => map.get(key),
+        Value::Array(items) => key.parse::<usize>().ok().and_then(|i| items.get(i)),
+        _ => None,
+    }
+}
+
+fn get_mut<'v>(parent: &'v mut Value, key: &str) -> Option<&'v mut Value> {
+    match parent {
+        Value::Object(map) => map.get_mut(key),
+        Value::Array(items) => key
+            .parse::<usize>()
+            .ok()
+            .and_then(move |i| items.get_mut(i)),
+        _ => None,
+    }
+}
+
+fn set(parent: &mut Value, key: &str, value: Value) -> Result<()> {
+    match get_mut(parent, key) {
+        Some(current) => *current = value,
+        None => match parent {
+            Value::Object(map) => {
+                map.insert(key.to_owned(), value);
+            }
+            _ => return Err(RedDbErrorKind::InvalidUpdate.into()),
+        },
+    }
+    Ok(())
+}
+
+fn remove(parent: &mut Value, key: &str) -> Option<Value> {
+    match parent {
+        Value::Object(map) => map.remove(key),
+        // array elements are nulled like in MongoDB, so later indexes don't shift
+        Value::Array(items) => key
+            .parse::<usize>()
+            .ok()
+            .and_then(|i| items.get_mut(i))
+            .map(|item| item.take()),
+        _ => None,
+    }
+}
+
+fn add(current: &Value, by: &Value) -> Result<Value> {
+    let (current, by) = match (current, by) {
+        (Value::Number(current), Value::Number(by)) => (current, by),
+        _ => return Err(RedDbErrorKind::InvalidUpdate.into()),
+    };
+
+    if let (Some(current), Some(by)) = (current.as_i64(), by.as_i64()) {
+        if let Some(sum) = current.checked_add(by) {
+            return Ok(Value::from(sum));
+        }
+    }
+    let sum = current.as_f64().unwrap_or_default() + by.as_f64().unwrap_or_default();
+    Ok(Value::Number(
+        Number::from_f64(sum).ok_or(RedDbErrorKind::InvalidUpdate)?,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn apply_operators() {
+        let mut doc = json!({
+            "name": "Ada",
+            "visits": 1,
+            "tags": ["admin"],
+            "draft": true
+        });
+        let update = Update::new()
+            .set("address.city", "Berlin")
+            .inc("visits", 2)
+            .inc("score", 0.5)
+            .push("tags", "dev")
+            .add_to_set("tags", "admin")
+            .pull("tags", "admin")
+            .unset("draft")
+            .rename("name", "first_name");
+
+        assert!(update.apply(&mut doc).unwrap());
+        assert_eq!(
+            doc,
+            json!({
+                "first_name": "Ada",
+                "visits": 3,
+                "score": 0.5,
+                "tags": ["dev"],
+                "address": { "city": "Berlin" }
+            })
+        );
+
+        let unchanged = Update::new()
+            .set("visits", 3)
+            .unset("draft")
+            .pull("tags", "x");
+        assert!(!unchanged.apply(&mut doc).unwrap());
+
+        let mut numbers = json!({ "values": [1.0, 2, 3.5] });
+        let update = Update::new().pull("values", 1).add_to_set("values", 2.0);
+        assert!(update.apply(&mut numbers).unwrap());
+        assert_eq!(numbers, json!({ "values": [2, 3.5] }));
+
+        let err = Update::new()
+            .inc("first_name", 1)
+            .apply(&mut doc)
+            .unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::InvalidUpdate);
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let update =
+            Update::from_json(r#"{"$inc":{"visits":1},"$rename":{"a":"b"},"$unset":{"draft":""}}"#)
+                .unwrap();
+        assert_eq!(
+            update,
+            Update::new()
+                .inc("visits", 1)
+                .rename("a", "b")
+                .unset("draft")
+        );
+        assert_eq!(
+            Update::from_json(&update.to_json().unwrap()).unwrap(),
+            update
+        );
+
+        let repeated = Update::new()
+            .push("tags", "a")
+            .push("tags", "a")
+            .inc("visits", 1)
+            .set("visits", 0);
+        assert_eq!(
+            repeated.to_json().unwrap(),
+            r#"[{"$push":{"tags":"a"}},{"$push":{"tags":"a"}},{"$inc":{"visits":1}},{"$set":{"visits":0}}]"#
+        );
+        assert_eq!(
+            Update::from_json(&repeated.to_json().unwrap()).unwrap(),
+            repeated
+        );
+
+        let err = Update::from_json(r#"{"$bogus":{"a":1}}"#).unwrap_err();
+        assert_eq!(err.kind(), RedDbErrorKind::InvalidUpdate);
+    }
+}
@@ -0,0 +1,57 @@
+use serde::{Deserialize, Serialize};
+use std::fmt::Debug;
+use uuid::Uuid;
+
+use crate::error::{RedDbErrorKind, Result};
+use crate::serializer::Serializer;
+use crate::status::Status;
+
+/// Number of changes a watcher can fall behind before it misses some.
+pub(crate) const WATCH_CAPACITY: usize = 1024;
+
+/// A persisted change to a document, as seen by watchers.
+///
+/// `before` is empty for inserts and `after` for deletes.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Change<T> {
+    pub _id: Uuid,
+    pub _st: Status,
+    pub before: Option<T>,
+    pub after: Option<T>,
+}
+
+/// Serialized form of a `Change`, shared by every watcher.
+#[derive(Debug)]
+pub(crate) struct Event {
+    pub id: Uuid,
+    pub status: Status,
+    pub rev: u64,
+    pub before: Option<Vec<u8>>,
+    pub after: Option<Vec<u8>>,
+}
+
+impl Event {
+    pub fn decode<T, SE>(&self, serializer: &SE) -> Result<Change<T>>
+    where
+        for<'de> T: Serialize + Deserialize<'de> + Debug,
+        for<'de> SE: Serializer<'de>,
+    {
+        let decode = |value: &Option<Vec<u8>>| -> Result<Option<T>> {
+            match value {
+                Some(value) => Ok(Some(
+                    serializer
+                        .deserialize(value)
+                        .map_err(|_| RedDbErrorKind::Deserialization)?,
+                )),
+                None => Ok(None),
+            }
+        };
+
+        Ok(Change {
+            _id: self.id,
+            _st: self.status.clone(),
+            before: decode(&self.before)?,
+            after: decode(&self.after)?,
+        })
+    }
+}